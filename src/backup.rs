@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Error, Kepler,
+    checksum::{Crc32c, crc32c},
+    constants::{BUF_SIZE, LEN_SIZE},
+    utils::{ensure_dir, read_u64, sync_dir},
+};
+
+const SST_FOOTER_SIZE: u64 = 48;
+
+/// Numbered backups of a `Kepler` database under one root directory.
+///
+/// Every backup starts as a checkpoint. SSTs are immutable once written,
+/// so they move into a shared `sst/` directory, named after their size and
+/// the checksums at their tail as well, and later backups only reference
+/// the ones already there without reading them again.
+/// Each backup records the size and CRC32C of every file it needs.
+/// A root holds backups of one database.
+pub struct BackupEngine {
+    root: PathBuf,
+}
+
+impl BackupEngine {
+    pub fn open<P: AsRef<Path>>(root: P) -> crate::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        ensure_dir(&root.join("sst"))?;
+
+        // Staging directories of interrupted backups were never published.
+        for entry in fs::read_dir(&root)? {
+            let path = entry?.path();
+            if file_name(&path).starts_with("tmp-") {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(Self { root })
+    }
+
+    /// Backs up every tree of `db` and returns the new backup id.
+    pub fn create_backup(&self, db: &Kepler) -> crate::Result<u64> {
+        let id = self.backups()?.last().map_or(1, |id| id + 1);
+        let staging = self.root.join(format!("tmp-{:06}", id));
+        db.checkpoint(&staging)?;
+
+        let known = self.shared_crcs()?;
+        let shared_dir = self.root.join("sst");
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(staging.join("sst"))? {
+            let path = entry?.path();
+            let mut file = BackupFile::shared(file_name(&path), &path)?;
+            let shared = shared_dir.join(file.shared_name());
+
+            // NOTE:
+            // Older backups may reference a shared file, so it is never
+            // replaced. Within one database an sstno is never reused, and
+            // the tail checksums cover the last data block, the index, the
+            // bloom filter and the footer, so a match is the same table
+            // and neither copy is read. Only new tables are checksummed.
+            file.crc = match known.get(&file.shared_name()) {
+                Some(crc) => *crc,
+                None => checksum_file(&path)?.1,
+            };
+            if shared.exists() {
+                fs::remove_file(&path)?;
+            } else {
+                File::open(&path)?.sync_all()?;
+                fs::rename(&path, &shared)?;
+            }
+            files.push(file);
+        }
+        sync_dir(&shared_dir)?;
+        fs::remove_dir(staging.join("sst"))?;
+
+        // The manifest and CURRENT.
+        for entry in fs::read_dir(&staging)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(BackupFile::read(file_name(&path), &path)?);
+            }
+        }
+        for entry in fs::read_dir(staging.join("wal"))? {
+            let path = entry?.path();
+            let name = format!("wal/{}", file_name(&path));
+            files.push(BackupFile::read(name, &path)?);
+        }
+
+        write_meta(&staging, &files)?;
+        sync_dir(&staging.join("wal"))?;
+        sync_dir(&staging)?;
+        fs::rename(&staging, self.backup_path(id))?;
+        sync_dir(&self.root)?;
+        Ok(id)
+    }
+
+    /// Ids of every published backup, oldest first.
+    pub fn backups(&self) -> crate::Result<Vec<u64>> {
+        let mut ids: Vec<u64> = fs::read_dir(&self.root)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| file_name(&e.path()).strip_prefix("backup-")?.parse().ok())
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Restores the newest backup into `target`, which must not exist yet.
+    pub fn restore_latest<P: AsRef<Path>>(&self, target: P) -> crate::Result<()> {
+        let latest = self.backups()?.last().copied();
+        let id = latest.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.restore_backup(id, target)
+    }
+
+    /// Restores backup `id` into `target`, which must not exist yet.
+    /// Fails with `Error::Corrupted` if a file does not match its checksum.
+    pub fn restore_backup<P: AsRef<Path>>(&self, id: u64, target: P) -> crate::Result<()> {
+        let target = target.as_ref();
+        let backup = self.backup_path(id);
+        let files = read_meta(&backup)?;
+
+        fs::create_dir_all(target.parent().unwrap_or(target))?;
+        fs::create_dir(target)?;
+        fs::create_dir(target.join("sst"))?;
+        fs::create_dir(target.join("wal"))?;
+
+        for file in files.iter() {
+            let dst = if file.shared {
+                target.join("sst").join(&file.name)
+            } else {
+                target.join(&file.name)
+            };
+            file.copy_verified(&self.file_path(&backup, file), &dst)?;
+        }
+        Ok(())
+    }
+
+    /// Checks every file of backup `id` against its recorded checksum.
+    pub fn verify_backup(&self, id: u64) -> crate::Result<()> {
+        let backup = self.backup_path(id);
+
+        for file in read_meta(&backup)?.iter() {
+            let (size, crc) = checksum_file(&self.file_path(&backup, file))?;
+            if size != file.size || crc != file.crc {
+                return Err(Error::Corrupted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes all but the newest `keep` backups,
+    /// then every shared SST no remaining backup references.
+    pub fn purge_old(&self, keep: usize) -> crate::Result<()> {
+        let ids = self.backups()?;
+        let (purged, kept) = ids.split_at(ids.len().saturating_sub(keep));
+
+        for id in purged {
+            fs::remove_dir_all(self.backup_path(*id))?;
+        }
+
+        let mut referenced = BTreeSet::new();
+        for id in kept {
+            for file in read_meta(&self.backup_path(*id))? {
+                if file.shared {
+                    referenced.insert(file.shared_name());
+                }
+            }
+        }
+
+        for entry in fs::read_dir(self.root.join("sst"))? {
+            let path = entry?.path();
+            if !referenced.contains(&file_name(&path)) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// CRC32C of every shared file a published backup references.
+    fn shared_crcs(&self) -> crate::Result<BTreeMap<String, u32>> {
+        let mut crcs = BTreeMap::new();
+        for id in self.backups()? {
+            for file in read_meta(&self.backup_path(id))? {
+                if file.shared {
+                    crcs.insert(file.shared_name(), file.crc);
+                }
+            }
+        }
+        Ok(crcs)
+    }
+
+    fn backup_path(&self, id: u64) -> PathBuf {
+        self.root.join(format!("backup-{:06}", id))
+    }
+
+    fn file_path(&self, backup: &Path, file: &BackupFile) -> PathBuf {
+        if file.shared {
+            self.root.join("sst").join(file.shared_name())
+        } else {
+            backup.join(&file.name)
+        }
+    }
+}
+
+/// A file a backup needs. Shared files live in the root `sst/` directory,
+/// the others in the backup directory itself.
+struct BackupFile {
+    shared: bool,
+    name: String,
+    size: u64,
+    crc: u32,
+    /// CRC32C of the checksums a shared SST ends with, 0 for other files.
+    tail: u32,
+}
+
+impl BackupFile {
+    /// Checksums a file that only this backup holds, fsyncing it as well.
+    fn read(name: String, path: &Path) -> crate::Result<Self> {
+        let (size, crc) = checksum_file(path)?;
+        File::open(path)?.sync_all()?;
+        Ok(Self {
+            shared: false,
+            name,
+            size,
+            crc,
+            tail: 0,
+        })
+    }
+
+    /// Identifies an SST by its size and tail without reading all of it.
+    /// The caller fills in `crc`.
+    fn shared(name: String, path: &Path) -> crate::Result<Self> {
+        let size = fs::metadata(path)?.len();
+        Ok(Self {
+            shared: true,
+            name,
+            size,
+            crc: 0,
+            tail: sst_tail_crc(path, size)?,
+        })
+    }
+
+    /// Name of a shared file in the root `sst/` directory. Tables that
+    /// share an sstno but differ in content never collide.
+    fn shared_name(&self) -> String {
+        let stem = self.name.strip_suffix(".log").unwrap_or(&self.name);
+        format!("{}-{}-{:08x}.log", stem, self.size, self.tail)
+    }
+
+    fn copy_verified(&self, src: &Path, dst: &Path) -> crate::Result<()> {
+        let mut reader = BufReader::with_capacity(BUF_SIZE, File::open(src)?);
+        let mut writer = BufWriter::with_capacity(BUF_SIZE, File::create(dst)?);
+        let mut crc = Crc32c::new();
+        let mut size = 0;
+        let mut buf = vec![0u8; BUF_SIZE];
+
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buf[..n]);
+            writer.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+
+        if size != self.size || crc.finish() != self.crc {
+            return Err(Error::Corrupted);
+        }
+        writer.flush()?;
+        writer.get_mut().sync_all()?;
+        Ok(())
+    }
+}
+
+/// CRC32C of the crcs of the last data block, the index block and the
+/// bloom filter of an SST, and of its footer.
+fn sst_tail_crc(path: &Path, size: u64) -> crate::Result<u32> {
+    let mut file = File::open(path)?;
+    let footer_start = size.checked_sub(SST_FOOTER_SIZE).ok_or(Error::Corrupted)?;
+    let mut footer = [0u8; SST_FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(footer_start))?;
+    file.read_exact(&mut footer)?;
+
+    let index_offset = read_u64(&footer, 0).ok_or(Error::Corrupted)?;
+    let bloom_offset = read_u64(&footer, 8).ok_or(Error::Corrupted)?;
+    let mut crc = Crc32c::new();
+    let mut block_crc = [0u8; LEN_SIZE];
+
+    // Every block ends with its crc, and an SST without data has none.
+    for end in [index_offset, bloom_offset, footer_start] {
+        let Some(start) = end.checked_sub(LEN_SIZE as u64) else {
+            continue;
+        };
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block_crc)?;
+        crc.update(&block_crc);
+    }
+    crc.update(&footer);
+    Ok(crc.finish())
+}
+
+fn checksum_file(path: &Path) -> crate::Result<(u64, u32)> {
+    let mut reader = BufReader::with_capacity(BUF_SIZE, File::open(path)?);
+    let mut crc = Crc32c::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; BUF_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok((size, crc.finish()));
+        }
+        crc.update(&buf[..n]);
+        size += n as u64;
+    }
+}
+
+/// Backup Meta Format
+///
+/// Entry
+///     - shared(1) + name_len(4) + name(name_len) + size(8) + crc(4) + tail(4)
+///
+/// Footer
+///     - crc32c of every entry(4)
+fn write_meta(backup: &Path, files: &[BackupFile]) -> crate::Result<()> {
+    let mut body: Vec<u8> = Vec::new();
+
+    for file in files.iter() {
+        body.push(file.shared as u8);
+        body.extend_from_slice(&(file.name.len() as u32).to_le_bytes());
+        body.extend_from_slice(file.name.as_bytes());
+        body.extend_from_slice(&file.size.to_le_bytes());
+        body.extend_from_slice(&file.crc.to_le_bytes());
+        body.extend_from_slice(&file.tail.to_le_bytes());
+    }
+    body.extend_from_slice(&crc32c(&body).to_le_bytes());
+
+    let mut meta = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(backup.join("meta"))?;
+    meta.write_all(&body)?;
+    meta.sync_all()?;
+    Ok(())
+}
+
+fn read_meta(backup: &Path) -> crate::Result<Vec<BackupFile>> {
+    let data = fs::read(backup.join("meta"))?;
+    let body_len = data.len().checked_sub(LEN_SIZE).ok_or(Error::Corrupted)?;
+    let (body, footer) = data.split_at(body_len);
+    if crc32c(body).to_le_bytes() != footer {
+        return Err(Error::Corrupted);
+    }
+
+    let mut files = Vec::new();
+    let mut idx = 0;
+    while idx < body.len() {
+        let name_start = idx + 1 + LEN_SIZE;
+        let name_len = u32::from_le_bytes(
+            body.get(idx + 1..name_start)
+                .ok_or(Error::Corrupted)?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let name_end = name_start + name_len;
+        let entry_end = name_end + 8 + 4 + 4;
+        if entry_end > body.len() {
+            return Err(Error::Corrupted);
+        }
+
+        files.push(BackupFile {
+            shared: body[idx] == 1,
+            name: String::from_utf8(body[name_start..name_end].to_vec())
+                .map_err(|_| Error::Corrupted)?,
+            size: u64::from_le_bytes(body[name_end..name_end + 8].try_into().unwrap()),
+            crc: u32::from_le_bytes(body[name_end + 8..name_end + 12].try_into().unwrap()),
+            tail: u32::from_le_bytes(body[name_end + 12..entry_end].try_into().unwrap()),
+        });
+        idx = entry_end;
+    }
+    Ok(files)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,109 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use bytes::Bytes;
+
+use crate::{Kepler, db::KeplerInner, types::Value, utils::now_millis};
+
+/// A set of writes applied atomically by `Kepler::write`.
+///
+/// The whole batch is framed as one WAL record and gets a contiguous
+/// seqno range, so readers and recovery observe all of it or none of it.
+/// Writes queued with the `_in` methods target another tree of the same
+/// database, which makes a batch atomic across trees. Writing a batch
+/// with trees of another database fails with `Error::UnknownTree`.
+#[derive(Default, Clone)]
+pub struct WriteBatch {
+    /// `None` targets the tree the batch is written to.
+    pub(crate) ops: Vec<(Option<u32>, Bytes, Value)>,
+    /// Database of the trees the `_in` methods were given.
+    pub(crate) owner: Option<Weak<KeplerInner>>,
+    /// Set once trees of two databases were given.
+    pub(crate) mixed: bool,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.push(None, key, Value::Data(Bytes::copy_from_slice(val)));
+    }
+
+    /// Like `put`, but the key reads as missing once `ttl` has elapsed.
+    pub fn put_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let val = Value::Expiring {
+            val: Bytes::copy_from_slice(val),
+            expires_at,
+        };
+        self.push(None, key, val);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.push(None, key, Value::Tombstone);
+    }
+
+    /// Queues `operand` to be folded into `key` by the `MergeOperator`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.push(None, key, Value::Merge(Bytes::copy_from_slice(operand)));
+    }
+
+    /// Like `put`, but writes to `tree`.
+    pub fn put_in(&mut self, tree: &Kepler, key: &[u8], val: &[u8]) {
+        let val = Value::Data(Bytes::copy_from_slice(val));
+        let id = self.tree_id(tree);
+        self.push(Some(id), key, val);
+    }
+
+    /// Like `remove`, but writes to `tree`.
+    pub fn remove_in(&mut self, tree: &Kepler, key: &[u8]) {
+        let id = self.tree_id(tree);
+        self.push(Some(id), key, Value::Tombstone);
+    }
+
+    /// Like `merge`, but writes to `tree`.
+    pub fn merge_in(&mut self, tree: &Kepler, key: &[u8], operand: &[u8]) {
+        let val = Value::Merge(Bytes::copy_from_slice(operand));
+        let id = self.tree_id(tree);
+        self.push(Some(id), key, val);
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.owner = None;
+        self.mixed = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Whether every tree given to the `_in` methods belongs to `db`.
+    pub(crate) fn belongs_to(&self, db: &KeplerInner) -> bool {
+        !self.mixed
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| std::ptr::eq(owner.as_ptr(), db))
+    }
+
+    fn tree_id(&mut self, tree: &Kepler) -> u32 {
+        match &self.owner {
+            Some(owner) => self.mixed |= !std::ptr::eq(owner.as_ptr(), &*tree.db),
+            None => self.owner = Some(Arc::downgrade(&tree.db)),
+        }
+        tree.tables.id
+    }
+
+    fn push(&mut self, tree: Option<u32>, key: &[u8], val: Value) {
+        self.ops.push((tree, Bytes::copy_from_slice(key), val));
+    }
+}
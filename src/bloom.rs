@@ -10,7 +10,7 @@ pub(crate) struct BloomFilter {
 impl BloomFilter {
     pub fn new(key_count: usize) -> Self {
         let bit_size = if key_count == 0 { 0 } else { key_count * 10 };
-        let byte_size = (bit_size + 7) / 8;
+        let byte_size = bit_size.div_ceil(8);
 
         Self {
             bits: vec![0u8; byte_size],
@@ -34,12 +34,12 @@ impl BloomFilter {
         self.bit_size
     }
 
-    pub fn add(&mut self, key: &[u8]) {
+    /// Adds a key by its `hash_key_split` hash.
+    pub fn add(&mut self, (hi, lo): (u64, u64)) {
         if self.bit_size == 0 {
             return;
         }
 
-        let (hi, lo) = hash_key_split(key);
         let bit_size = self.bit_size;
 
         for i in 0..HASH_COUNT {
@@ -73,7 +73,7 @@ impl BloomFilter {
     }
 }
 
-fn hash_key_split(mut key: &[u8]) -> (u64, u64) {
+pub(crate) fn hash_key_split(mut key: &[u8]) -> (u64, u64) {
     let hash = murmur3_x64_128(&mut key, HASH_SEED).unwrap();
     let hi = hash as u64;
     let lo = (hash >> 64) as u64;
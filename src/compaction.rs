@@ -0,0 +1,605 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    path::Path,
+    sync::{
+        Arc,
+        mpsc::{Receiver, Sender, SyncSender, sync_channel},
+    },
+    thread::{self, JoinHandle},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    constants::{
+        L0_COMPACTION_TRIGGER, L1_MAX_BYTES, LEVEL_SIZE_MULTIPLIER, MAX_LEVELS, SST_TARGET_SIZE,
+    },
+    manifest::Manifest,
+    merge::MergeOperator,
+    snapshot::{SnapshotList, VersionGc},
+    sst_manager::SSTManager,
+    sst_writer::{FlushResult, SSTBuilder},
+    sstable::{SSTIter, SSTable},
+    types::{Value, Versions, WorkerSignal, stop_worker},
+};
+
+pub(crate) struct Compactor {
+    sender: SyncSender<WorkerSignal>,
+    handle: JoinHandle<()>,
+}
+
+impl Compactor {
+    pub(crate) fn new(
+        path: &Path,
+        manifest: Arc<Manifest>,
+        sst_manager: Arc<SSTManager>,
+        snapshots: Arc<SnapshotList>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        err_tx: Sender<WorkerSignal>,
+    ) -> crate::Result<Self> {
+        let (compact_tx, compact_rx) = sync_channel::<WorkerSignal>(1);
+
+        let handle = start_compaction_thread(
+            path,
+            manifest,
+            sst_manager,
+            snapshots,
+            merge_operator,
+            compact_rx,
+            err_tx,
+        )?;
+
+        Ok(Self {
+            sender: compact_tx,
+            handle,
+        })
+    }
+
+    /// Wakes the compaction worker. A pending wake-up already covers
+    /// every flush that happened before it runs, so a full channel is fine.
+    pub(crate) fn notify(&self) {
+        let _ = self.sender.try_send(WorkerSignal::Compact);
+    }
+
+    /// Lets the running compaction finish, then joins the worker.
+    pub(crate) fn shutdown(self) -> crate::Result<()> {
+        stop_worker(&self.sender, self.handle)
+    }
+}
+
+fn start_compaction_thread(
+    path: &Path,
+    manifest: Arc<Manifest>,
+    sst_manager: Arc<SSTManager>,
+    snapshots: Arc<SnapshotList>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compact_rx: Receiver<WorkerSignal>,
+    err_tx: Sender<WorkerSignal>,
+) -> crate::Result<JoinHandle<()>> {
+    let sst_dir_path = path.join("sst");
+
+    let handle = thread::spawn(move || {
+        let mut pointers: Vec<Option<Bytes>> = vec![None; MAX_LEVELS];
+
+        let mut process = || -> crate::Result<()> {
+            while let Ok(WorkerSignal::Compact) = compact_rx.recv() {
+                while let Some(task) = pick_compaction(&sst_manager.levels()?, &mut pointers) {
+                    let gc = VersionGc::new(snapshots.seqnos()?, merge_operator.clone());
+                    run_compaction(&sst_dir_path, &manifest, &sst_manager, task, gc)?;
+                }
+            }
+            Ok(())
+        };
+
+        if let Err(e) = process() {
+            let _ = err_tx.send(WorkerSignal::Panic(e));
+        }
+    });
+
+    Ok(handle)
+}
+
+pub(crate) struct CompactionTask {
+    level: usize,
+    /// Ordered newest first; earlier tables shadow later ones on equal keys.
+    inputs: Vec<Arc<SSTable>>,
+    /// No level below the output holds any key in the inputs' range.
+    bottommost: bool,
+}
+
+fn max_bytes_for_level(level: usize) -> usize {
+    L1_MAX_BYTES * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1)
+}
+
+pub(crate) fn pick_compaction(
+    levels: &[Vec<Arc<SSTable>>],
+    pointers: &mut [Option<Bytes>],
+) -> Option<CompactionTask> {
+    if levels[0].len() >= L0_COMPACTION_TRIGGER {
+        return Some(with_overlaps(levels, 0, levels[0].clone()));
+    }
+
+    for level in 1..MAX_LEVELS - 1 {
+        let size: usize = levels[level].iter().map(|t| t.size()).sum();
+        if size <= max_bytes_for_level(level) {
+            continue;
+        }
+
+        // Walk the key space round-robin so every table gets its turn.
+        let table = levels[level]
+            .iter()
+            .find(|t| {
+                pointers[level]
+                    .as_ref()
+                    .is_none_or(|p| t.smallest() > &p[..])
+            })
+            .unwrap_or(&levels[level][0])
+            .clone();
+        pointers[level] = Some(Bytes::copy_from_slice(table.largest()));
+
+        return Some(with_overlaps(levels, level, vec![table]));
+    }
+    None
+}
+
+fn with_overlaps(
+    levels: &[Vec<Arc<SSTable>>],
+    level: usize,
+    upper: Vec<Arc<SSTable>>,
+) -> CompactionTask {
+    let smallest = upper.iter().map(|t| t.smallest()).min().unwrap_or_default();
+    let largest = upper.iter().map(|t| t.largest()).max().unwrap_or_default();
+    let lower: Vec<Arc<SSTable>> = levels[level + 1]
+        .iter()
+        .filter(|t| t.overlaps(smallest, largest))
+        .cloned()
+        .collect();
+
+    // L0 is kept in flush order, so the newest table is the last one.
+    let mut inputs: Vec<Arc<SSTable>> = upper.iter().rev().cloned().collect();
+    inputs.extend(lower);
+
+    let smallest = inputs
+        .iter()
+        .map(|t| t.smallest())
+        .min()
+        .unwrap_or_default();
+    let largest = inputs.iter().map(|t| t.largest()).max().unwrap_or_default();
+    let bottommost = levels
+        .iter()
+        .skip(level + 2)
+        .flatten()
+        .all(|t| !t.overlaps(smallest, largest));
+
+    CompactionTask {
+        level,
+        inputs,
+        bottommost,
+    }
+}
+
+pub(crate) fn run_compaction(
+    sst_dir_path: &Path,
+    manifest: &Manifest,
+    sst_manager: &SSTManager,
+    task: CompactionTask,
+    gc: VersionGc,
+) -> crate::Result<()> {
+    let mut output = CompactionOutput::new(sst_dir_path, sst_manager, task.level + 1);
+    let mut current: Option<(Bytes, Versions)> = None;
+
+    let iters = task.inputs.iter().map(|t| t.iter()).collect();
+
+    // NOTE:
+    // At the bottommost level no older version of a key can exist below,
+    // so a tombstone ending its history shadows nothing and is dropped.
+    let collapse = |key: &Bytes, versions: Versions| -> crate::Result<Versions> {
+        let mut kept = gc.collapse(key, versions)?;
+        if task.bottommost && matches!(kept.last(), Some((_, Value::Tombstone))) {
+            kept.pop();
+        }
+        Ok(kept)
+    };
+
+    for entry in MergeIter::new(iters) {
+        let (key, seqno, val) = entry?;
+
+        match current.as_mut() {
+            Some((k, versions)) if *k == key => versions.push((seqno, val)),
+            _ => {
+                if let Some((k, versions)) = current.replace((key, vec![(seqno, val)])) {
+                    output.add(&k, collapse(&k, versions)?)?;
+                }
+            }
+        }
+    }
+    if let Some((k, versions)) = current {
+        output.add(&k, collapse(&k, versions)?)?;
+    }
+
+    let (outputs, mut edit) = output.finish()?;
+
+    let removed: Vec<u64> = task.inputs.iter().map(|t| t.id).collect();
+    for input in task.inputs.iter() {
+        edit.push(FlushResult::new(
+            1,
+            sst_manager.tree,
+            input.level as u8,
+            input.id,
+            input.max_seqno,
+            input.min_seqno,
+        ));
+    }
+
+    manifest.send_batch(edit)?;
+    sst_manager.apply_compaction(&removed, outputs)
+}
+
+/// Output tables of a compaction, rolled over at `SST_TARGET_SIZE`.
+struct CompactionOutput<'a> {
+    sst_dir_path: &'a Path,
+    sst_manager: &'a SSTManager,
+    level: usize,
+    builder: Option<SSTBuilder>,
+    outputs: Vec<SSTable>,
+    edit: Vec<FlushResult>,
+}
+
+impl<'a> CompactionOutput<'a> {
+    fn new(sst_dir_path: &'a Path, sst_manager: &'a SSTManager, level: usize) -> Self {
+        Self {
+            sst_dir_path,
+            sst_manager,
+            level,
+            builder: None,
+            outputs: Vec::new(),
+            edit: Vec::new(),
+        }
+    }
+
+    /// Adds every surviving version of a key, newest first.
+    /// Versions of a key never span two output tables.
+    fn add(&mut self, key: &Bytes, versions: Versions) -> crate::Result<()> {
+        if versions.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(b) = self
+            .builder
+            .take_if(|b| b.estimated_size() >= SST_TARGET_SIZE)
+        {
+            self.seal(b)?;
+        }
+
+        let b = match self.builder {
+            Some(ref mut b) => b,
+            None => self.builder.insert(SSTBuilder::new(
+                self.sst_dir_path,
+                self.sst_manager.tree,
+                self.sst_manager.get_id(),
+                self.level,
+                self.sst_manager.compression.clone(),
+            )?),
+        };
+        for (seqno, val) in versions.iter() {
+            b.add(key, *seqno, val)?;
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self, builder: SSTBuilder) -> crate::Result<()> {
+        let (table, result) = builder.finish()?;
+        self.outputs.push(table);
+        self.edit.push(result);
+        Ok(())
+    }
+
+    fn finish(mut self) -> crate::Result<(Vec<SSTable>, Vec<FlushResult>)> {
+        if let Some(b) = self.builder.take().filter(|b| !b.is_empty()) {
+            self.seal(b)?;
+        }
+        Ok((self.outputs, self.edit))
+    }
+}
+
+struct HeapEntry {
+    key: Bytes,
+    seqno: u64,
+    val: Value,
+    source: usize,
+}
+
+impl Ord for HeapEntry {
+    // `BinaryHeap` pops the greatest entry first, so the smallest key,
+    // then the highest seqno, then the newest source must compare greatest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .key
+            .cmp(&self.key)
+            .then(self.seqno.cmp(&other.seqno))
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// K-way merge over sorted SST iterators.
+/// Equal keys come out newest first.
+struct MergeIter {
+    iters: Vec<SSTIter>,
+    heap: BinaryHeap<HeapEntry>,
+    pending: Vec<usize>,
+}
+
+impl MergeIter {
+    fn new(iters: Vec<SSTIter>) -> Self {
+        let pending = (0..iters.len()).collect();
+        Self {
+            iters,
+            heap: BinaryHeap::new(),
+            pending,
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = crate::Result<(Bytes, u64, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(source) = self.pending.pop() {
+            match self.iters[source].next() {
+                Some(Ok((key, seqno, val))) => self.heap.push(HeapEntry {
+                    key,
+                    seqno,
+                    val,
+                    source,
+                }),
+                Some(Err(e)) => return Some(Err(e)),
+                None => {}
+            }
+        }
+
+        let top = self.heap.pop()?;
+        self.pending.push(top.source);
+        Some(Ok((top.key, top.seqno, top.val)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::Compression, constants::DEFAULT_TREE, sst_writer::SSTBuilder, traits::Getable,
+        version::Version,
+    };
+    use std::{
+        collections::BTreeMap,
+        sync::{atomic::AtomicU64, mpsc::channel},
+    };
+    use tempfile::tempdir;
+
+    fn open_manager(dir: &Path) -> crate::Result<SSTManager> {
+        let version = Version::new(BTreeMap::new(), 0, 1);
+        SSTManager::open(
+            dir,
+            &version,
+            DEFAULT_TREE,
+            Arc::new(AtomicU64::new(1)),
+            Compression::Lz,
+        )
+    }
+
+    fn build(
+        dir: &Path,
+        manager: &SSTManager,
+        entries: &[(&str, &str)],
+        seqno: u64,
+    ) -> crate::Result<()> {
+        let mut builder = SSTBuilder::new(dir, DEFAULT_TREE, manager.get_id(), 0, Compression::Lz)?;
+        for (k, v) in entries {
+            builder.add(
+                &Bytes::from(k.to_string()),
+                seqno,
+                &Value::Data(Bytes::from(v.to_string())),
+            )?;
+        }
+        let (table, _) = builder.finish()?;
+        manager.push(table)
+    }
+
+    #[test]
+    fn l0_compaction_into_l1() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let (err_tx, _err_rx) = channel();
+        let (manifest, _) = Manifest::new(dir.path(), err_tx)?;
+        let manager = open_manager(dir.path())?;
+        let sst_dir = dir.path().join("sst");
+        let mut pointers = vec![None; MAX_LEVELS];
+
+        build(&sst_dir, &manager, &[("a", "1"), ("b", "1"), ("c", "1")], 1)?;
+        build(&sst_dir, &manager, &[("b", "2"), ("d", "2")], 2)?;
+        build(&sst_dir, &manager, &[("a", "3"), ("e", "3")], 3)?;
+        assert!(pick_compaction(&manager.levels()?, &mut pointers).is_none());
+
+        build(&sst_dir, &manager, &[("c", "4")], 4)?;
+        let task = pick_compaction(&manager.levels()?, &mut pointers).unwrap();
+        run_compaction(
+            &sst_dir,
+            &manifest,
+            &manager,
+            task,
+            VersionGc::new(vec![], None),
+        )?;
+
+        let levels = manager.levels()?;
+        assert!(levels[0].is_empty());
+        assert_eq!(levels[1].len(), 1);
+        assert_eq!(levels[1][0].smallest(), b"a");
+        assert_eq!(levels[1][0].largest(), b"e");
+
+        assert_eq!(manager.get(b"a", u64::MAX)?, Some(Bytes::from("3")));
+        assert_eq!(manager.get(b"b", u64::MAX)?, Some(Bytes::from("2")));
+        assert_eq!(manager.get(b"c", u64::MAX)?, Some(Bytes::from("4")));
+        assert_eq!(manager.get(b"d", u64::MAX)?, Some(Bytes::from("2")));
+        assert_eq!(manager.get(b"f", u64::MAX)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn l0_compaction_merges_overlapping_l1() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let (err_tx, _err_rx) = channel();
+        let (manifest, _) = Manifest::new(dir.path(), err_tx)?;
+        let manager = open_manager(dir.path())?;
+        let sst_dir = dir.path().join("sst");
+        let mut pointers = vec![None; MAX_LEVELS];
+
+        for round in 0..2u64 {
+            for i in 0..L0_COMPACTION_TRIGGER as u64 {
+                let val = format!("{round}-{i}");
+                build(&sst_dir, &manager, &[("k", val.as_str())], round * 10 + i)?;
+            }
+            let task = pick_compaction(&manager.levels()?, &mut pointers).unwrap();
+            run_compaction(
+                &sst_dir,
+                &manifest,
+                &manager,
+                task,
+                VersionGc::new(vec![], None),
+            )?;
+        }
+
+        let levels = manager.levels()?;
+        assert!(levels[0].is_empty());
+        assert_eq!(levels[1].len(), 1);
+        assert_eq!(manager.get(b"k", u64::MAX)?, Some(Bytes::from("1-3")));
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_versions_pinned_by_snapshots() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let (err_tx, _err_rx) = channel();
+        let (manifest, _) = Manifest::new(dir.path(), err_tx)?;
+        let manager = open_manager(dir.path())?;
+        let sst_dir = dir.path().join("sst");
+        let mut pointers = vec![None; MAX_LEVELS];
+
+        for i in 1..=L0_COMPACTION_TRIGGER as u64 {
+            let val = format!("v{i}");
+            build(&sst_dir, &manager, &[("k", val.as_str())], i * 10)?;
+        }
+        let task = pick_compaction(&manager.levels()?, &mut pointers).unwrap();
+        run_compaction(
+            &sst_dir,
+            &manifest,
+            &manager,
+            task,
+            VersionGc::new(vec![25], None),
+        )?;
+
+        assert_eq!(manager.get(b"k", u64::MAX)?, Some(Bytes::from("v4")));
+        assert_eq!(manager.get(b"k", 25)?, Some(Bytes::from("v2")));
+        assert_eq!(manager.get(b"k", 15)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn tombstones_shadow_older_tables() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let (err_tx, _err_rx) = channel();
+        let (manifest, _) = Manifest::new(dir.path(), err_tx)?;
+        let manager = open_manager(dir.path())?;
+        let sst_dir = dir.path().join("sst");
+        let mut pointers = vec![None; MAX_LEVELS];
+
+        build(&sst_dir, &manager, &[("a", "1"), ("b", "1")], 1)?;
+        let mut builder =
+            SSTBuilder::new(&sst_dir, DEFAULT_TREE, manager.get_id(), 0, Compression::Lz)?;
+        builder.add(&Bytes::from("a"), 2, &Value::Tombstone)?;
+        let (table, _) = builder.finish()?;
+        manager.push(table)?;
+
+        assert!(matches!(
+            manager.lookup(b"a", u64::MAX)?,
+            Some((2, Value::Tombstone))
+        ));
+        assert_eq!(manager.get(b"a", 1)?, Some(Bytes::from("1")));
+
+        build(&sst_dir, &manager, &[("c", "3")], 3)?;
+        build(&sst_dir, &manager, &[("d", "4")], 4)?;
+        let task = pick_compaction(&manager.levels()?, &mut pointers).unwrap();
+        run_compaction(
+            &sst_dir,
+            &manifest,
+            &manager,
+            task,
+            VersionGc::new(vec![], None),
+        )?;
+
+        assert_eq!(manager.get(b"a", u64::MAX)?, None);
+        assert_eq!(manager.get(b"b", u64::MAX)?, Some(Bytes::from("1")));
+        Ok(())
+    }
+
+    #[test]
+    fn expired_entries_are_dropped_by_compaction() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let (err_tx, _err_rx) = channel();
+        let (manifest, _) = Manifest::new(dir.path(), err_tx)?;
+        let manager = open_manager(dir.path())?;
+        let sst_dir = dir.path().join("sst");
+        let mut pointers = vec![None; MAX_LEVELS];
+
+        let mut builder =
+            SSTBuilder::new(&sst_dir, DEFAULT_TREE, manager.get_id(), 0, Compression::Lz)?;
+        let expiring = |expires_at| Value::Expiring {
+            val: Bytes::from("session"),
+            expires_at,
+        };
+        builder.add(&Bytes::from("gone"), 1, &expiring(1))?;
+        builder.add(&Bytes::from("live"), 1, &expiring(u64::MAX))?;
+        let (table, _) = builder.finish()?;
+        manager.push(table)?;
+
+        assert_eq!(manager.get(b"gone", u64::MAX)?, None);
+        assert_eq!(
+            manager.get(b"live", u64::MAX)?,
+            Some(Bytes::from("session"))
+        );
+
+        for i in 2..=L0_COMPACTION_TRIGGER as u64 {
+            build(&sst_dir, &manager, &[("other", "v")], i)?;
+        }
+        let task = pick_compaction(&manager.levels()?, &mut pointers).unwrap();
+        run_compaction(
+            &sst_dir,
+            &manifest,
+            &manager,
+            task,
+            VersionGc::new(vec![], None),
+        )?;
+
+        let levels = manager.levels()?;
+        let keys: Vec<Bytes> = levels[1][0].iter().map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![Bytes::from("live"), Bytes::from("other")]);
+        assert_eq!(
+            manager.get(b"live", u64::MAX)?,
+            Some(Bytes::from("session"))
+        );
+        Ok(())
+    }
+}
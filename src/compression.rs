@@ -0,0 +1,256 @@
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{Error, constants::LEN_SIZE, utils::read_u32};
+
+const NONE_ID: u8 = 0;
+const LZ_ID: u8 = 1;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// A user codec for SST data blocks. Registered once, when the database is opened.
+pub trait Compressor: Send + Sync {
+    /// Stored with every block it compresses. Ids 0 and 1 belong to the
+    /// built-in codecs and are refused on open. The id of a codec must never change.
+    fn id(&self) -> u8;
+
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Returns `None` if `data` is not the output of `compress`.
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Codec applied to each data block of new SSTs.
+///
+/// Blocks keep the id of their codec, so tables written under another
+/// setting stay readable as long as a custom codec they use is registered.
+#[derive(Clone, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Built-in LZ77 codec, fast and dependency-free.
+    Lz,
+    Custom(Arc<dyn Compressor>),
+}
+
+impl Compression {
+    /// Fails for a custom codec claiming the id of a built-in one.
+    pub(crate) fn validate(&self) -> crate::Result<()> {
+        match self {
+            Compression::Custom(c) if matches!(c.id(), NONE_ID | LZ_ID) => {
+                Err(Error::ReservedCompression { id: c.id() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => NONE_ID,
+            Compression::Lz => LZ_ID,
+            Compression::Custom(c) => c.id(),
+        }
+    }
+
+    /// Returns the codec id and the block to store.
+    /// A block that does not shrink is stored as is.
+    pub(crate) fn compress<'a>(&self, block: &'a [u8]) -> (u8, Cow<'a, [u8]>) {
+        let out = match self {
+            Compression::None => return (NONE_ID, Cow::Borrowed(block)),
+            Compression::Lz => lz_compress(block),
+            Compression::Custom(c) => c.compress(block),
+        };
+
+        if out.len() < block.len() {
+            (self.id(), Cow::Owned(out))
+        } else {
+            (NONE_ID, Cow::Borrowed(block))
+        }
+    }
+
+    /// Returns `None` if `data` fails to decompress.
+    pub(crate) fn decompress<'a>(
+        &self,
+        id: u8,
+        data: &'a [u8],
+    ) -> crate::Result<Option<Cow<'a, [u8]>>> {
+        let out = match (id, self) {
+            (NONE_ID, _) => return Ok(Some(Cow::Borrowed(data))),
+            (LZ_ID, _) => lz_decompress(data),
+            (id, Compression::Custom(c)) if c.id() == id => c.decompress(data),
+            (id, _) => return Err(Error::UnknownCompression { id }),
+        };
+        Ok(out.map(Cow::Owned))
+    }
+}
+
+/// LZ77 in the style of the LZ4 block format, preceded by the raw length.
+///
+/// Sequence
+///     - token(1) + [lit_len extension] + literals + offset(2)
+///         + [match_len extension]
+///     - token holds lit_len and match_len - 4 in a nibble each,
+///       a nibble of 15 continues in bytes of 255 ended by a smaller one
+///     - the last sequence holds only literals
+pub(crate) fn lz_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(LEN_SIZE + data.len() / 2);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    // Last position + 1 of each hashed 4-byte prefix, 16KB on the stack.
+    // Positions past u32::MAX wrap, which only costs a missed match.
+    let mut table = [0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+
+    while i + MIN_MATCH <= data.len() {
+        let prefix = u32::from_le_bytes(data[i..i + MIN_MATCH].try_into().unwrap());
+        let slot = (prefix.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = (table[slot] as usize).checked_sub(1);
+        table[slot] = (i + 1) as u32;
+
+        if let Some(c) = candidate
+            && c < i
+            && i - c <= MAX_OFFSET
+            && data[c..c + MIN_MATCH] == data[i..i + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while i + len < data.len() && data[c + len] == data[i + len] {
+                len += 1;
+            }
+            write_sequence(&mut out, &data[anchor..i], Some((i - c, len)));
+            i += len;
+            anchor = i;
+        } else {
+            i += 1;
+        }
+    }
+
+    write_sequence(&mut out, &data[anchor..], None);
+    out
+}
+
+pub(crate) fn lz_decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let len = read_u32(data, 0)? as usize;
+    let mut out = Vec::with_capacity(len);
+    let mut idx = LEN_SIZE;
+
+    loop {
+        let token = *data.get(idx)?;
+        idx += 1;
+
+        let lit_len = read_len(data, &mut idx, (token >> 4) as usize)?;
+        out.extend_from_slice(data.get(idx..idx.checked_add(lit_len)?)?);
+        idx += lit_len;
+        if idx == data.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(data.get(idx..idx + 2)?.try_into().ok()?) as usize;
+        idx += 2;
+        let match_len = read_len(data, &mut idx, (token & 0x0f) as usize)? + MIN_MATCH;
+        let start = out.len().checked_sub(offset).filter(|_| offset > 0)?;
+        if out.len() + match_len > len {
+            return None;
+        }
+
+        // Byte by byte, since a match may overlap its own output.
+        for k in start..start + match_len {
+            out.push(out[k]);
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let match_len = m.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) << 4) | match_len.min(15)) as u8);
+    write_len(out, literals.len());
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = m {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        write_len(out, match_len);
+    }
+}
+
+/// Writes the part of `len` that does not fit its token nibble.
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    let Some(mut rest) = len.checked_sub(15) else {
+        return;
+    };
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn read_len(data: &[u8], idx: &mut usize, nibble: usize) -> Option<usize> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let b = *data.get(*idx)?;
+            *idx += 1;
+            len += b as usize;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lz_round_trips_and_shrinks_repetitive_json() {
+        let json: Vec<u8> = (0..200)
+            .flat_map(|i| format!(r#"{{"id":{i},"name":"user-{i}","active":true}},"#).into_bytes())
+            .collect();
+        let long_run = vec![b'a'; 5000];
+        let samples: [&[u8]; 5] = [b"", b"abc", b"abcdabcdabcdabcd", &json, &long_run];
+
+        for data in samples {
+            let packed = lz_compress(data);
+            assert_eq!(lz_decompress(&packed).as_deref(), Some(data));
+        }
+        assert!(lz_compress(&json).len() * 3 < json.len());
+    }
+
+    #[test]
+    fn malformed_lz_input_is_rejected() {
+        let packed = lz_compress(b"abcdabcdabcdabcdabcd");
+        assert_eq!(lz_decompress(&packed[..packed.len() - 1]), None);
+
+        // A match reaching back before the start of the output.
+        let mut bad = 8u32.to_le_bytes().to_vec();
+        bad.extend_from_slice(&[0x00, 0x09, 0x00]);
+        assert_eq!(lz_decompress(&bad), None);
+    }
+
+    #[test]
+    fn custom_codec_cannot_take_a_builtin_id() {
+        struct Fixed(u8);
+        impl Compressor for Fixed {
+            fn id(&self) -> u8 {
+                self.0
+            }
+            fn compress(&self, data: &[u8]) -> Vec<u8> {
+                data.to_vec()
+            }
+            fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+                Some(data.to_vec())
+            }
+        }
+
+        for id in [NONE_ID, LZ_ID] {
+            let c = Compression::Custom(Arc::new(Fixed(id)));
+            assert!(matches!(c.validate(), Err(Error::ReservedCompression { id: i }) if i == id));
+        }
+        assert!(Compression::Custom(Arc::new(Fixed(7))).validate().is_ok());
+    }
+}
@@ -1,11 +1,22 @@
 pub const WAL_CAP_LIMIT: usize = 64 * 1024 * 1024;
-pub const WAL_HEADER_SIZE: usize = 17;
+pub const WAL_HEADER_SIZE: usize = 25;
 pub const HASH_SEED: u32 = 3141592;
 pub const HASH_COUNT: usize = 7;
 pub const LEN_SIZE: usize = 4;
 pub const OFFSET_SIZE: usize = 8;
 pub const PAGE_4KB: usize = 4096;
+pub const RESTART_INTERVAL: usize = 16;
 pub const ACTIVE_CAP_MAX: usize = 32 * 1024 * 1024;
 pub const BUF_SIZE: usize = 64 * 1024;
 pub const SEQNO_SIZE: usize = 8;
 pub const MAGIC: u64 = 0x3141592653897932;
+pub const MANIFEST_RECORD_SIZE: usize = 30;
+pub const MANIFEST_REWRITE_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_LEVELS: usize = 7;
+pub const L0_COMPACTION_TRIGGER: usize = 4;
+pub const L1_MAX_BYTES: usize = 256 * 1024 * 1024;
+pub const LEVEL_SIZE_MULTIPLIER: usize = 10;
+pub const SST_TARGET_SIZE: usize = 64 * 1024 * 1024;
+pub const VALUE_TYPE_SIZE: usize = 1;
+pub const TREE_ID_SIZE: usize = 4;
+pub const DEFAULT_TREE: u32 = 0;
@@ -1,97 +1,806 @@
 use crate::{
     Error,
-    journal::Journal,
-    manifest::Manifest,
+    batch::WriteBatch,
+    compression::Compression,
+    constants::DEFAULT_TREE,
+    error::CompareAndSwapError,
+    iter::Iter,
+    journal::{Journal, WalMark, WalSyncer},
+    manifest::{LiveSet, Manifest, write_manifest},
     mem_table::MemTable,
-    sst_manager::SSTManager,
+    merge::MergeOperator,
+    options::{Options, WalRecoveryMode, WriteOptions},
+    snapshot::{Snapshot, SnapshotList},
+    sst_manager::{SSTManager, move_orphans},
+    sst_writer::{FlushResult, create_sst_path},
     table_set::TableSet,
     traits::{Getable, Putable},
-    types::WorkerSignal,
-    utils::ensure_dir,
+    transaction::Transaction,
+    types::{Value, WorkerSignal},
+    utils::{ensure_dir, owned_bounds, prefix_bounds, sync_dir, write_synced},
     version::Version,
+    write_group::WriteGroup,
 };
 use bytes::Bytes;
 use std::{
-    path::Path,
+    collections::BTreeMap,
+    fs,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
     sync::{
-        Arc, Mutex,
-        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{Receiver, Sender, channel},
     },
+    time::Duration,
 };
 
 impl Clone for Kepler {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            db: self.db.clone(),
+            tables: self.tables.clone(),
+        }
     }
 }
 
-pub struct Kepler(pub(crate) Arc<KeplerInner>);
+/// A handle to one tree of a database.
+///
+/// `Kepler::new` returns the default tree and `open_tree` any named one.
+/// Every tree has its own MemTable and SSTs, while all of them share
+/// the WAL, the manifest and the seqno counter.
+pub struct Kepler {
+    pub(crate) db: Arc<KeplerInner>,
+    pub(crate) tables: Arc<TableSet>,
+}
 
 impl Kepler {
     pub fn new<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
-        Ok(Self(Arc::new(KeplerInner::new(path.as_ref())?)))
+        Self::with_options(path, Options::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(path: P, options: Options) -> crate::Result<Self> {
+        options.compression.validate()?;
+        let db = Arc::new(KeplerInner::new(path.as_ref(), options)?);
+        let tables = db.tree(DEFAULT_TREE)?;
+        Ok(Self { db, tables })
+    }
+
+    /// Opens the database with `merge_operator` folding the operands
+    /// written by `merge`. The same operator must be used on every open.
+    pub fn with_merge_operator<P, M>(path: P, merge_operator: M) -> crate::Result<Self>
+    where
+        P: AsRef<Path>,
+        M: MergeOperator + 'static,
+    {
+        let options = Options {
+            merge_operator: Some(Arc::new(merge_operator)),
+            ..Options::default()
+        };
+        Self::with_options(path, options)
+    }
+
+    /// Opens the tree called `name`, creating it on first use.
+    /// Handles to the same name share one keyspace.
+    pub fn open_tree(&self, name: &str) -> crate::Result<Kepler> {
+        let tables = self.db.open_tree(name)?;
+        Ok(Self {
+            db: self.db.clone(),
+            tables,
+        })
     }
 
     pub fn insert(&self, key: &[u8], val: &[u8]) -> crate::Result<()> {
-        self.0.put(key, Some(val))
+        self.insert_opt(key, val, &self.db.write_options)
+    }
+
+    /// Like `insert`, with the durability of `opts`.
+    pub fn insert_opt(&self, key: &[u8], val: &[u8], opts: &WriteOptions) -> crate::Result<()> {
+        self.db.put(&self.tables, key, Some(val), opts)
+    }
+
+    /// Inserts `key` so that it reads as missing once `ttl` has elapsed.
+    /// Flush and compaction drop it for good after that.
+    pub fn insert_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key, val, ttl);
+        self.write(batch)
     }
 
     pub fn remove(&self, key: &[u8]) -> crate::Result<()> {
-        self.0.put(key, None)
+        self.remove_opt(key, &self.db.write_options)
+    }
+
+    /// Like `remove`, with the durability of `opts`.
+    pub fn remove_opt(&self, key: &[u8], opts: &WriteOptions) -> crate::Result<()> {
+        self.db.put(&self.tables, key, None, opts)
     }
 
     pub fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        self.0.get(key)
+        self.db.get(&self.tables, key)
+    }
+
+    /// Records `operand` for `key` without reading it first.
+    /// Operands are folded by the `MergeOperator` on read, flush and compaction.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> crate::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch)
+    }
+
+    /// Swaps the value of `key` to `new` only if it currently is `expected`.
+    /// `None` stands for an absent key on either side.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::Result<Result<(), CompareAndSwapError>> {
+        self.db.compare_and_swap(&self.tables, key, expected, new)
+    }
+
+    /// Replaces the value of `key` with `f(old)` and returns the new value.
+    /// Returning `None` from `f` removes the key.
+    ///
+    /// `f` may run more than once when other writers race on `key`,
+    /// so it should not have side effects.
+    pub fn update_and_fetch<V, F>(&self, key: &[u8], f: F) -> crate::Result<Option<Bytes>>
+    where
+        V: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+    {
+        self.db.update_and_fetch(&self.tables, key, f)
+    }
+
+    /// Applies every write of `batch` atomically, across trees too.
+    /// Writes without a tree go to this one.
+    pub fn write(&self, batch: WriteBatch) -> crate::Result<()> {
+        self.write_opt(batch, &self.db.write_options)
+    }
+
+    /// Like `write`, with the durability of `opts`.
+    pub fn write_opt(&self, batch: WriteBatch, opts: &WriteOptions) -> crate::Result<()> {
+        self.db.write(&self.tables, batch, opts)
+    }
+
+    /// Iterates over every live key in ascending order.
+    pub fn iter(&self) -> crate::Result<Iter> {
+        self.db
+            .range_at(&self.tables, Bound::Unbounded, Bound::Unbounded, u64::MAX)
+    }
+
+    /// Iterates over the live keys within `range` in ascending order.
+    /// The returned iterator is double-ended.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> crate::Result<Iter> {
+        let (lower, upper) = owned_bounds(range);
+        self.db.range_at(&self.tables, lower, upper, u64::MAX)
+    }
+
+    /// Iterates over every live key starting with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> crate::Result<Iter> {
+        let (lower, upper) = prefix_bounds(prefix);
+        self.db.range_at(&self.tables, lower, upper, u64::MAX)
+    }
+
+    /// Pins the current state. Reads through the snapshot ignore
+    /// every write made after it was taken.
+    pub fn snapshot(&self) -> crate::Result<Snapshot> {
+        Snapshot::new(self.db.clone(), self.tables.clone())
+    }
+
+    /// Writes a consistent copy of every tree into `dir`, which must not
+    /// exist yet. The copy opens as an independent database.
+    ///
+    /// SSTs are hard-linked, so the cost is mostly the active WAL file,
+    /// during which writers wait.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> crate::Result<()> {
+        self.db.checkpoint(dir.as_ref())
+    }
+
+    /// Writes the tree's MemTable out as an SST and blocks until
+    /// the manifest records it.
+    pub fn flush(&self) -> crate::Result<()> {
+        self.db.flush(&self.tables)
+    }
+
+    /// Writes buffered WAL records to the OS, and to disk if `sync`.
+    pub fn flush_wal(&self, sync: bool) -> crate::Result<()> {
+        self.db.flush_wal(sync)
+    }
+
+    /// Flushes every ImmTable, waits for pending manifest writes,
+    /// fsyncs the WAL and joins the background workers.
+    ///
+    /// Every handle of the database is closed, and later calls fail with
+    /// `Error::Closed`. Dropping the last handle closes it as well,
+    /// but this is the only way to see a worker error.
+    pub fn close(self) -> crate::Result<()> {
+        self.db.close()
+    }
+
+    /// Starts an optimistic transaction.
+    /// Its writes are applied on `commit` only if nothing it read changed.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.db.clone(), self.tables.clone())
     }
 }
 
+/// A plain write queued for group commit.
+pub(crate) struct WriteRequest {
+    tables: Arc<TableSet>,
+    write: Write,
+    opts: WriteOptions,
+}
+
+enum Write {
+    Put(Bytes, Option<Bytes>),
+    Batch(WriteBatch),
+}
+
 pub struct KeplerInner {
     pub seqno: AtomicU64,
-    pub tables: TableSet,
-    pub journal: Mutex<Journal>,
-    #[allow(dead_code)]
+    pub journal: Arc<Mutex<Journal>>,
     pub manifest: Arc<Manifest>,
-    pub(crate) err_rx: Receiver<WorkerSignal>,
+    pub(crate) snapshots: Arc<SnapshotList>,
+    pub(crate) err_rx: Mutex<Receiver<WorkerSignal>>,
+    pub(crate) write_options: WriteOptions,
+    syncer: Mutex<Option<WalSyncer>>,
+    writers: WriteGroup<WriteRequest>,
+    closed: AtomicBool,
+    path: PathBuf,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    compression: Compression,
+    err_tx: Sender<WorkerSignal>,
+    sst_ids: Arc<AtomicU64>,
+    trees: RwLock<BTreeMap<u32, Arc<TableSet>>>,
+    tree_names: Mutex<BTreeMap<String, u32>>,
 }
 
 impl KeplerInner {
-    pub fn new(path: &Path) -> crate::Result<Self> {
+    pub fn new(path: &Path, options: Options) -> crate::Result<Self> {
         ensure_dir(path)?;
         let (err_tx, err_rx) = channel::<WorkerSignal>();
         let (manifest, version) = Self::open_manifest(path, err_tx.clone())?;
-        let sst_manager = SSTManager::open(path, version.next_sstno)?;
-        let (journal, mem, next_inner_seqno) =
-            Self::open_storage_components(path, version.next_seqno)?;
-        Ok(Self {
+        move_orphans(path, &version)?;
+        let (journal, mut mems, next_inner_seqno) =
+            Self::open_storage_components(path, &version, options.wal_recovery_mode, &manifest)?;
+
+        let journal = Arc::new(Mutex::new(journal));
+        let syncer = options
+            .wal_sync_interval
+            .map(|interval| WalSyncer::start(journal.clone(), interval, err_tx.clone()));
+
+        let inner = Self {
             seqno: AtomicU64::new(next_inner_seqno),
-            tables: TableSet::new(path, sst_manager, mem, manifest.clone(), err_tx)?,
-            journal: Mutex::new(journal),
+            journal,
             manifest,
-            err_rx,
-        })
+            snapshots: Arc::new(SnapshotList::new()),
+            err_rx: Mutex::new(err_rx),
+            write_options: options.write_options,
+            syncer: Mutex::new(syncer),
+            writers: WriteGroup::new(),
+            closed: AtomicBool::new(false),
+            path: path.to_path_buf(),
+            merge_operator: options.merge_operator,
+            compression: options.compression,
+            err_tx,
+            sst_ids: Arc::new(AtomicU64::new(version.next_sstno)),
+            trees: RwLock::new(BTreeMap::new()),
+            tree_names: Mutex::new(version.trees.clone()),
+        };
+
+        let ids = std::iter::once(DEFAULT_TREE).chain(version.trees.values().copied());
+        for id in ids {
+            let mem = mems.remove(&id).unwrap_or_default();
+            inner.add_tree(id, &version, mem)?;
+        }
+        Ok(inner)
+    }
+
+    /// Returns the tree called `name`, registering it in the manifest
+    /// before first use so that WAL records never name an unknown tree.
+    fn open_tree(&self, name: &str) -> crate::Result<Arc<TableSet>> {
+        let mut names = self.tree_names.lock().map_err(|_| Error::Poisoned)?;
+        if let Some(id) = names.get(name) {
+            return self.tree(*id);
+        }
+
+        let id = names.values().max().copied().unwrap_or(DEFAULT_TREE) + 1;
+        self.manifest.create_tree(id, name)?;
+        names.insert(name.to_string(), id);
+
+        let empty = Version::new(BTreeMap::new(), 0, 0);
+        self.add_tree(id, &empty, MemTable::new())
+    }
+
+    fn add_tree(&self, id: u32, version: &Version, mem: MemTable) -> crate::Result<Arc<TableSet>> {
+        let sst_manager = SSTManager::open(
+            &self.path,
+            version,
+            id,
+            self.sst_ids.clone(),
+            self.compression.clone(),
+        )?;
+        let tables = Arc::new(TableSet::new(
+            &self.path,
+            sst_manager,
+            mem,
+            self.manifest.clone(),
+            self.snapshots.clone(),
+            self.merge_operator.clone(),
+            self.err_tx.clone(),
+        )?);
+
+        self.trees
+            .write()
+            .map_err(|_| Error::Poisoned)?
+            .insert(id, tables.clone());
+        Ok(tables)
+    }
+
+    fn tree(&self, id: u32) -> crate::Result<Arc<TableSet>> {
+        self.trees
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .get(&id)
+            .cloned()
+            .ok_or(Error::UnknownTree)
     }
 
-    pub fn put(&self, key: &[u8], val: Option<&[u8]>) -> crate::Result<()> {
+    pub fn put(
+        &self,
+        tables: &Arc<TableSet>,
+        key: &[u8],
+        val: Option<&[u8]>,
+        opts: &WriteOptions,
+    ) -> crate::Result<()> {
         self.check_thread_error()?;
+        let request = WriteRequest {
+            tables: tables.clone(),
+            write: Write::Put(Bytes::copy_from_slice(key), val.map(Bytes::copy_from_slice)),
+            opts: *opts,
+        };
+        self.writers
+            .commit(request, |group| self.commit_group(group))
+    }
+
+    fn put_locked(
+        &self,
+        journal: &mut Journal,
+        tables: &TableSet,
+        key: &[u8],
+        val: Option<&[u8]>,
+        opts: &WriteOptions,
+    ) -> crate::Result<()> {
+        let mark = journal.mark().map_err(|_| Error::Poisoned)?;
+        let seqno = self
+            .log_put(journal, tables, key, val, opts)
+            .and_then(|seqno| {
+                journal.flush(opts.sync).map_err(|_| Error::Poisoned)?;
+                Ok(seqno)
+            })
+            .inspect_err(|_| self.rollback(journal, mark))?;
+        tables.put(seqno, key, val)
+    }
+
+    // NOTE:
+    // Every writer takes the journal lock, so reading the current value
+    // while holding it makes the read and the write a single step.
+    pub fn compare_and_swap(
+        &self,
+        tables: &TableSet,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::Result<Result<(), CompareAndSwapError>> {
+        self.check_thread_error()?;
+        let mut journal = self.lock_journal()?;
+
+        let current = tables.get(key, u64::MAX)?;
+        if current.as_deref() != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        self.put_locked(&mut journal, tables, key, new, &self.write_options)?;
+        Ok(Ok(()))
+    }
+
+    pub fn update_and_fetch<V, F>(
+        &self,
+        tables: &TableSet,
+        key: &[u8],
+        mut f: F,
+    ) -> crate::Result<Option<Bytes>>
+    where
+        V: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+    {
+        // NOTE:
+        // `f` runs without the journal lock, so it may use the database itself.
+        // A concurrent write to `key` fails the swap and `f` runs again on the
+        // value that won.
+        let mut current = self.get(tables, key)?;
+        loop {
+            let new = f(current.as_deref());
+            let new = new.as_ref().map(|v| v.as_ref());
+
+            match self.compare_and_swap(tables, key, current.as_deref(), new)? {
+                Ok(()) => return Ok(new.map(Bytes::copy_from_slice)),
+                Err(e) => current = e.current,
+            }
+        }
+    }
+
+    pub fn write(
+        &self,
+        tables: &Arc<TableSet>,
+        batch: WriteBatch,
+        opts: &WriteOptions,
+    ) -> crate::Result<()> {
+        self.check_thread_error()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if !batch.belongs_to(self) {
+            return Err(Error::UnknownTree);
+        }
+        if !tables.has_merge_operator()
+            && batch
+                .ops
+                .iter()
+                .any(|(_, _, v)| matches!(v, Value::Merge(_)))
+        {
+            return Err(Error::NoMergeOperator);
+        }
+
+        let request = WriteRequest {
+            tables: tables.clone(),
+            write: Write::Batch(batch),
+            opts: *opts,
+        };
+        self.writers
+            .commit(request, |group| self.commit_group(group))
+    }
+
+    // NOTE:
+    // The leader logs the whole group, then flushes and fsyncs once
+    // before applying any of it. Seqnos and MemTable inserts still
+    // happen under the journal lock, like for every other writer.
+    fn commit_group(&self, group: &[WriteRequest]) -> Vec<crate::Result<()>> {
+        let fail_all = |closed: bool| {
+            let err = || {
+                if closed {
+                    Error::Closed
+                } else {
+                    Error::Poisoned
+                }
+            };
+            group.iter().map(|_| Err(err())).collect()
+        };
+
+        let mut journal = match self.lock_journal() {
+            Ok(journal) => journal,
+            Err(e) => return fail_all(matches!(e, Error::Closed)),
+        };
+        let Ok(mark) = journal.mark() else {
+            return fail_all(false);
+        };
+
+        let mut logged = Vec::with_capacity(group.len());
+        for request in group.iter() {
+            let entry = match &request.write {
+                Write::Put(key, val) => self
+                    .log_put(
+                        &mut journal,
+                        &request.tables,
+                        key,
+                        val.as_deref(),
+                        &request.opts,
+                    )
+                    .map(|seqno| (seqno, BTreeMap::new())),
+                Write::Batch(batch) => {
+                    self.log_batch(&mut journal, &request.tables, batch, &request.opts)
+                }
+            };
+            match entry {
+                Err(Error::Poisoned) => {
+                    self.rollback(&mut journal, mark);
+                    return fail_all(false);
+                }
+                entry => logged.push(entry),
+            }
+        }
+
+        let sync = group.iter().any(|r| r.opts.sync && !r.opts.disable_wal);
+        if journal.flush(sync).is_err() {
+            self.rollback(&mut journal, mark);
+            return fail_all(false);
+        }
+
+        logged
+            .into_iter()
+            .zip(group.iter())
+            .map(|(entry, request)| {
+                let (seqno, targets) = entry?;
+                match &request.write {
+                    Write::Put(key, val) => request.tables.put(seqno, key, val.as_deref()),
+                    Write::Batch(batch) => {
+                        Self::apply_batch(&request.tables, batch, seqno, &targets)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Applies `batch` while the caller holds the journal lock.
+    /// Writes without a tree go to `tables`.
+    pub(crate) fn write_locked(
+        &self,
+        journal: &mut Journal,
+        tables: &TableSet,
+        batch: &WriteBatch,
+        opts: &WriteOptions,
+    ) -> crate::Result<()> {
+        let mark = journal.mark().map_err(|_| Error::Poisoned)?;
+        let (seqno, targets) = self
+            .log_batch(journal, tables, batch, opts)
+            .and_then(|logged| {
+                journal.flush(opts.sync).map_err(|_| Error::Poisoned)?;
+                Ok(logged)
+            })
+            .inspect_err(|_| self.rollback(journal, mark))?;
+        Self::apply_batch(tables, batch, seqno, &targets)
+    }
+
+    /// Drops the WAL records of writes about to be reported as failed,
+    /// so that replay never brings them back. If that fails too,
+    /// the engine is poisoned.
+    fn rollback(&self, journal: &mut Journal, mark: WalMark) {
+        if let Err(e) = journal.rollback(mark) {
+            let _ = self.err_tx.send(WorkerSignal::Panic(Error::Io(e)));
+        }
+    }
+
+    fn log_put(
+        &self,
+        journal: &mut Journal,
+        tables: &TableSet,
+        key: &[u8],
+        val: Option<&[u8]>,
+        opts: &WriteOptions,
+    ) -> crate::Result<u64> {
         let seqno = self.seqno.fetch_add(1, Ordering::Relaxed);
 
-        let mut journal = self.journal.lock().map_err(|_| Error::Poisoned)?;
+        if opts.disable_wal {
+            tables.note_unlogged();
+        } else {
+            journal
+                .insert(seqno, tables.id, key, val)
+                .map_err(|_| Error::Poisoned)?;
+        }
+        Ok(seqno)
+    }
+
+    /// Resolves the trees `batch` writes to besides `tables`, takes its
+    /// seqnos and appends its WAL record. Returns the first seqno.
+    ///
+    /// NOTE:
+    /// Seqnos are taken under the journal lock so that WAL order
+    /// matches seqno order and the batch range stays contiguous.
+    fn log_batch(
+        &self,
+        journal: &mut Journal,
+        tables: &TableSet,
+        batch: &WriteBatch,
+        opts: &WriteOptions,
+    ) -> crate::Result<(u64, BTreeMap<u32, Arc<TableSet>>)> {
+        let mut targets: BTreeMap<u32, Arc<TableSet>> = BTreeMap::new();
+        for (tree, _, _) in batch.ops.iter() {
+            if let Some(id) = tree
+                && *id != tables.id
+                && !targets.contains_key(id)
+            {
+                targets.insert(*id, self.tree(*id)?);
+            }
+        }
 
-        journal
-            .insert(seqno, key, val)
-            .map_err(|_| Error::Poisoned)?;
+        let seqno = self.seqno.fetch_add(batch.len() as u64, Ordering::Relaxed);
 
-        self.tables.put(seqno, key, val)
+        if opts.disable_wal {
+            tables.note_unlogged();
+            targets.values().for_each(|target| target.note_unlogged());
+        } else {
+            journal
+                .insert_batch(seqno, tables.id, batch)
+                .map_err(|_| Error::Poisoned)?;
+        }
+        Ok((seqno, targets))
     }
 
-    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+    fn apply_batch(
+        tables: &TableSet,
+        batch: &WriteBatch,
+        seqno: u64,
+        targets: &BTreeMap<u32, Arc<TableSet>>,
+    ) -> crate::Result<()> {
+        let ops = || (seqno..).zip(batch.ops.iter());
+        tables.put_batch(
+            ops()
+                .filter(|(_, (tree, _, _))| tree.is_none_or(|id| id == tables.id))
+                .map(|(s, (_, key, val))| (s, key, val)),
+        )?;
+        for (id, target) in targets.iter() {
+            target.put_batch(
+                ops()
+                    .filter(|(_, (tree, _, _))| *tree == Some(*id))
+                    .map(|(s, (_, key, val))| (s, key, val)),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, tables: &TableSet, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        self.get_at(tables, key, u64::MAX)
+    }
+
+    pub fn get_at(
+        &self,
+        tables: &TableSet,
+        key: &[u8],
+        seqno: u64,
+    ) -> crate::Result<Option<Bytes>> {
+        self.check_thread_error()?;
+        tables.get(key, seqno)
+    }
+
+    /// Returns the latest version of `key` and the seqno it was written at.
+    pub(crate) fn lookup(
+        &self,
+        tables: &TableSet,
+        key: &[u8],
+    ) -> crate::Result<Option<(u64, Value)>> {
+        self.check_thread_error()?;
+        tables.lookup(key, u64::MAX)
+    }
+
+    pub fn range_at(
+        &self,
+        tables: &TableSet,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        seqno: u64,
+    ) -> crate::Result<Iter> {
         self.check_thread_error()?;
-        self.tables.get(key)
+        tables.range(lower, upper, seqno)
     }
 
-    fn check_thread_error(&self) -> crate::Result<()> {
-        match self.err_rx.try_recv() {
+    // NOTE:
+    // The WAL is copied and the SST set captured under the journal lock.
+    // Every write not yet in a captured SST is then in the copied WAL,
+    // and the trimmed manifest makes replay skip what the SSTs hold.
+    pub(crate) fn checkpoint(&self, dir: &Path) -> crate::Result<()> {
+        self.check_thread_error()?;
+        fs::create_dir_all(dir.parent().unwrap_or(dir))?;
+        fs::create_dir(dir)?;
+
+        let mut live = LiveSet::default();
+        let ssts = {
+            let mut journal = self.lock_journal()?;
+            journal.copy_to(&dir.join("wal"))?;
+
+            // Read before the SSTs, which are pushed ahead of their edits,
+            // so every seqno counted as flushed is in a captured table.
+            let flushed = self.manifest.flushed();
+            for (tree, from) in flushed.lock().map_err(|_| Error::Poisoned)?.iter() {
+                live.note_flushed(*tree, *from);
+            }
+            live.next_seqno = live.next_seqno.max(self.seqno.load(Ordering::Relaxed));
+            live.next_sstno = live.next_sstno.max(self.sst_ids.load(Ordering::Relaxed));
+
+            live.trees = self.tree_names.lock().map_err(|_| Error::Poisoned)?.clone();
+            let mut ssts = Vec::new();
+            for (id, tables) in self.trees.read().map_err(|_| Error::Poisoned)?.iter() {
+                ssts.extend(tables.live_ssts()?.into_iter().map(|t| (*id, t)));
+            }
+            ssts
+        };
+
+        let sst_dir_path = self.path.join("sst");
+        let target_sst_dir = dir.join("sst");
+        fs::create_dir(&target_sst_dir)?;
+
+        for (tree, table) in ssts.iter() {
+            let dst = create_sst_path(&target_sst_dir, table.id);
+
+            // A table compacted away since the capture may already be
+            // unlinked, but its mapping is still readable.
+            if fs::hard_link(create_sst_path(&sst_dir_path, table.id), &dst).is_err() {
+                write_synced(&dst, table.as_bytes())?;
+            }
+            live.apply(&FlushResult::new(
+                0,
+                *tree,
+                table.level as u8,
+                table.id,
+                table.max_seqno,
+                table.min_seqno,
+            ));
+        }
+        sync_dir(&target_sst_dir)?;
+
+        write_manifest(dir, &live)
+    }
+
+    /// Registers a snapshot at the last applied seqno.
+    ///
+    /// NOTE:
+    /// Writers apply to the MemTable and rotate it while holding the
+    /// journal lock. Pinning under the same lock guarantees that a
+    /// frozen MemTable never holds a seqno above a snapshot that a
+    /// concurrent flush has not seen yet.
+    pub(crate) fn pin_snapshot(&self) -> crate::Result<u64> {
+        let _journal = self.lock_journal()?;
+        let seqno = self.seqno.load(Ordering::Relaxed) - 1;
+        self.snapshots.acquire(seqno)?;
+        Ok(seqno)
+    }
+
+    pub(crate) fn flush(&self, tables: &TableSet) -> crate::Result<()> {
+        self.check_thread_error()?;
+        {
+            let _journal = self.lock_journal()?;
+            tables.freeze()?;
+        }
+        tables.wait_flushed()?;
+        self.check_thread_error()
+    }
+
+    pub(crate) fn flush_wal(&self, sync: bool) -> crate::Result<()> {
+        self.check_thread_error()?;
+        self.lock_journal()?.flush(sync).map_err(Error::Io)
+    }
+
+    /// Takes the journal lock, failing once the database is closed.
+    pub(crate) fn lock_journal(&self) -> crate::Result<MutexGuard<'_, Journal>> {
+        let journal = self.journal.lock().map_err(|_| Error::Poisoned)?;
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        Ok(journal)
+    }
+
+    // NOTE:
+    // Closing holds the journal lock throughout, so no write lands
+    // after the trees are flushed and the WAL is synced. Writes made
+    // without the WAL exist only in memory, so their MemTables are
+    // frozen and flushed along with the ImmTables.
+    fn close(&self) -> crate::Result<()> {
+        let syncer = self.syncer.lock().map_err(|_| Error::Poisoned)?.take();
+        let mut result = syncer.map_or(Ok(()), WalSyncer::shutdown);
+
+        let mut journal = self.lock_journal()?;
+        self.closed.store(true, Ordering::Release);
+
+        for tables in self.trees.read().map_err(|_| Error::Poisoned)?.values() {
+            result = result.and(tables.freeze_unlogged());
+            result = result.and(tables.shutdown());
+        }
+        result = result.and(self.manifest.shutdown());
+        result = result.and(journal.flush(true).map_err(Error::Io));
+
+        let err_rx = self.err_rx.lock().map_err(|_| Error::Poisoned)?;
+        if let Ok(WorkerSignal::Panic(e)) = err_rx.try_recv() {
+            return Err(e);
+        }
+        result
+    }
+
+    pub(crate) fn check_thread_error(&self) -> crate::Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        let err_rx = self.err_rx.lock().map_err(|_| Error::Poisoned)?;
+        match err_rx.try_recv() {
             Ok(WorkerSignal::Panic(e)) => Err(e),
             _ => Ok(()),
         }
@@ -101,10 +810,29 @@ impl KeplerInner {
         path: &Path,
         err_tx: Sender<WorkerSignal>,
     ) -> crate::Result<(Arc<Manifest>, Version)> {
-        Ok(Manifest::new(path, err_tx).map_err(|_| Error::Unrecoverable)?)
+        Manifest::new(path, err_tx).map_err(|e| match e {
+            Error::Corrupted => e,
+            _ => Error::Unrecoverable,
+        })
     }
 
-    fn open_storage_components(path: &Path, seqno: u64) -> crate::Result<(Journal, MemTable, u64)> {
-        Ok(Journal::open(path, seqno).map_err(|_| Error::Unrecoverable)?)
+    fn open_storage_components(
+        path: &Path,
+        version: &Version,
+        mode: WalRecoveryMode,
+        manifest: &Manifest,
+    ) -> crate::Result<(Journal, BTreeMap<u32, MemTable>, u64)> {
+        Journal::open(path, version, mode, manifest.flushed()).map_err(|e| match e {
+            Error::WalCorrupted { .. } => e,
+            _ => Error::Unrecoverable,
+        })
+    }
+}
+
+impl Drop for KeplerInner {
+    fn drop(&mut self) {
+        if !self.closed.load(Ordering::Acquire) {
+            let _ = self.close();
+        }
     }
 }
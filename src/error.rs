@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use std::io;
 use thiserror::Error;
 
@@ -13,6 +14,36 @@ pub enum Error {
     #[error("Wal or Manifest corruption")]
     Corrupted,
 
+    #[error("WAL corruption in file {file} at offset {offset}")]
+    WalCorrupted { file: u64, offset: u64 },
+
+    #[error("SST {sstno} is missing or corrupted")]
+    SstCorrupted { sstno: u64 },
+
+    #[error("SST block compressed with unregistered codec {id}")]
+    UnknownCompression { id: u8 },
+
+    #[error("Custom codec id {id} is reserved for a built-in codec")]
+    ReservedCompression { id: u8 },
+
+    #[error("Transaction conflict; a read key was overwritten")]
+    Conflict,
+
+    #[error("Timed out waiting for a key lock")]
+    LockTimeout,
+
+    #[error("Deadlock detected while waiting for a key lock")]
+    Deadlock,
+
+    #[error("Batch targets a tree this database does not hold")]
+    UnknownTree,
+
+    #[error("Merge operand found but no merge operator is registered")]
+    NoMergeOperator,
+
+    #[error("Database is closed")]
+    Closed,
+
     #[error("Previous write failed; engine poisoned")]
     Poisoned,
 
@@ -20,4 +51,12 @@ pub enum Error {
     Unrecoverable,
 }
 
+/// Returned by `Kepler::compare_and_swap` when the current value
+/// of the key is not the expected one.
+#[derive(Debug, Error)]
+#[error("Compare and swap failed; current value differs")]
+pub struct CompareAndSwapError {
+    pub current: Option<Bytes>,
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
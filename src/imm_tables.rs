@@ -3,21 +3,15 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use bytes::Bytes;
-
 use crate::{
     Error,
     traits::Getable,
-    types::{TableMap, Value},
+    types::{TableMap, Value, newest_visible},
 };
 
 impl Getable for ImmTables {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        match self.lookup_latest(key)? {
-            Some(Value::Data(v)) => Ok(Some(v)),
-            Some(Value::Tombstone) => Ok(None),
-            _ => Ok(None),
-        }
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        self.lookup_latest(key, seqno)
     }
 }
 
@@ -37,11 +31,22 @@ impl ImmTables {
         Ok(self.0.write().map_err(|_| Error::Poisoned)?.pop_front())
     }
 
-    fn lookup_latest(&self, key: &[u8]) -> crate::Result<Option<Value>> {
+    /// Returns the queued tables, oldest first.
+    pub fn tables(&self) -> crate::Result<Vec<Arc<TableMap>>> {
+        Ok(self
+            .0
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    fn lookup_latest(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
         let tables = self.0.read().map_err(|_| Error::Poisoned)?;
 
         for table in tables.iter().rev() {
-            if let Some((_seqno, val)) = table.get(key) {
+            if let Some(val) = table.get(key).and_then(|v| newest_visible(v, seqno)) {
                 return Ok(Some(val.clone()));
             }
         }
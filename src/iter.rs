@@ -0,0 +1,297 @@
+use std::{collections::VecDeque, ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    merge::{MergeOperator, resolve},
+    types::{Entry, TableMap, Value},
+    utils::{SliceRange, as_slice_bound, is_valid_range},
+};
+
+pub(crate) type Source = Box<dyn DoubleEndedIterator<Item = crate::Result<Entry>> + Send>;
+
+/// Ordered iterator over the live keys of a `Kepler` instance.
+///
+/// Merges the active MemTable, every ImmTable and all SSTables.
+/// Only the newest version of each key visible at the iterator's seqno
+/// is returned, with merge operands folded in, and tombstones are hidden.
+pub struct Iter {
+    sources: Vec<Peeked>,
+    seqno: u64,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl Iter {
+    /// `sources` must be ordered newest first.
+    /// On equal seqnos the earlier source wins.
+    pub(crate) fn new(
+        sources: Vec<Source>,
+        seqno: u64,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+    ) -> Self {
+        Self {
+            sources: sources.into_iter().map(Peeked::new).collect(),
+            seqno,
+            merge_operator,
+        }
+    }
+
+    /// Picks the next key from one end, drains every version of it
+    /// from all sources and resolves the visible ones, if any.
+    fn step(&mut self, forward: bool) -> Option<crate::Result<Option<Entry>>> {
+        let mut target: Option<Bytes> = None;
+
+        for source in self.sources.iter_mut() {
+            match source.peek(forward) {
+                Some(Ok((key, _, _))) => {
+                    let closer = target
+                        .as_ref()
+                        .is_none_or(|t| if forward { key < t } else { key > t });
+                    if closer {
+                        target = Some(key.clone());
+                    }
+                }
+                Some(Err(_)) => return source.take(forward).map(|r| r.map(Some)),
+                None => {}
+            }
+        }
+
+        let target = target?;
+        let mut visible: Vec<(u64, Value)> = Vec::new();
+
+        for source in self.sources.iter_mut() {
+            while let Some(peeked) = source.peek(forward) {
+                match peeked {
+                    Ok((key, _, _)) if *key == target => {}
+                    Ok(_) => break,
+                    Err(_) => return source.take(forward).map(|r| r.map(Some)),
+                }
+
+                if let Some(Ok((_, seqno, val))) = source.take(forward)
+                    && seqno <= self.seqno
+                {
+                    visible.push((seqno, val));
+                }
+            }
+        }
+
+        // The sort is stable, so on equal seqnos the earlier source stays first.
+        visible.sort_by_key(|(seqno, _)| std::cmp::Reverse(*seqno));
+        visible.dedup_by_key(|(seqno, _)| *seqno);
+
+        let resolved = resolve(
+            self.merge_operator.as_deref(),
+            &target,
+            visible.into_iter().map(Ok),
+        );
+        Some(resolved.map(|r| r.map(|(seqno, val)| (target, seqno, val))))
+    }
+
+    fn next_live(&mut self, forward: bool) -> Option<crate::Result<(Bytes, Bytes)>> {
+        loop {
+            match self.step(forward)? {
+                Ok(Some((key, _, Value::Data(val)))) => return Some(Ok((key, val))),
+                Ok(Some(_)) | Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = crate::Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_live(true)
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_live(false)
+    }
+}
+
+/// A source with one peeked slot per end.
+/// Once the source runs dry, the slot of the other end is handed over,
+/// so an entry is never yielded twice.
+struct Peeked {
+    iter: Source,
+    front: Option<crate::Result<Entry>>,
+    back: Option<crate::Result<Entry>>,
+}
+
+impl Peeked {
+    fn new(iter: Source) -> Self {
+        Self {
+            iter,
+            front: None,
+            back: None,
+        }
+    }
+
+    fn peek(&mut self, forward: bool) -> Option<&crate::Result<Entry>> {
+        if forward {
+            if self.front.is_none() {
+                self.front = self.iter.next().or_else(|| self.back.take());
+            }
+            self.front.as_ref()
+        } else {
+            if self.back.is_none() {
+                self.back = self.iter.next_back().or_else(|| self.front.take());
+            }
+            self.back.as_ref()
+        }
+    }
+
+    fn take(&mut self, forward: bool) -> Option<crate::Result<Entry>> {
+        if forward {
+            self.front.take()
+        } else {
+            self.back.take()
+        }
+    }
+}
+
+/// Bounded iterator over an immutable `TableMap`.
+/// Each step re-seeks the map from the last key taken at that end
+/// and buffers every version of the key it lands on.
+pub(crate) struct TableMapIter {
+    map: Arc<TableMap>,
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    front_buf: VecDeque<Entry>,
+    back_buf: VecDeque<Entry>,
+}
+
+impl TableMapIter {
+    pub(crate) fn new(map: Arc<TableMap>, lower: Bound<Bytes>, upper: Bound<Bytes>) -> Self {
+        Self {
+            map,
+            lower,
+            upper,
+            front_buf: VecDeque::new(),
+            back_buf: VecDeque::new(),
+        }
+    }
+
+    fn seek(&mut self, forward: bool) -> Option<VecDeque<Entry>> {
+        if !is_valid_range(&self.lower, &self.upper) {
+            return None;
+        }
+
+        let bounds: SliceRange = (as_slice_bound(&self.lower), as_slice_bound(&self.upper));
+        let mut range = self.map.range::<[u8], _>(bounds);
+        let (key, versions) = if forward {
+            range.next()?
+        } else {
+            range.next_back()?
+        };
+
+        if forward {
+            self.lower = Bound::Excluded(key.clone());
+        } else {
+            self.upper = Bound::Excluded(key.clone());
+        }
+        Some(
+            versions
+                .iter()
+                .rev()
+                .map(|(seqno, val)| (key.clone(), *seqno, val.clone()))
+                .collect(),
+        )
+    }
+}
+
+impl Iterator for TableMapIter {
+    type Item = crate::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front_buf.is_empty() {
+            match self.seek(true) {
+                Some(versions) => self.front_buf = versions,
+                None => return self.back_buf.pop_front().map(Ok),
+            }
+        }
+        self.front_buf.pop_front().map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for TableMapIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back_buf.is_empty() {
+            match self.seek(false) {
+                Some(versions) => self.back_buf = versions,
+                None => return self.front_buf.pop_back().map(Ok),
+            }
+        }
+        self.back_buf.pop_back().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::Compression, constants::DEFAULT_TREE, sst_writer::SSTBuilder, sstable::SSTable,
+    };
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    fn data(v: &str) -> Value {
+        Value::Data(Bytes::from(v.to_string()))
+    }
+
+    fn collect(iter: impl Iterator<Item = crate::Result<(Bytes, Bytes)>>) -> Vec<(Bytes, Bytes)> {
+        iter.map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn merge_shadows_and_hides_tombstones() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let mut builder = SSTBuilder::new(dir.path(), DEFAULT_TREE, 1, 0, Compression::None)?;
+        for k in ["a", "b", "c", "d"] {
+            builder.add(&Bytes::from(k), 1, &data("old"))?;
+        }
+        let (table, _) = builder.finish()?;
+        let table: Arc<SSTable> = Arc::new(table);
+
+        let mut map = TableMap::new();
+        map.insert(Bytes::from("b"), vec![(5, data("new"))]);
+        map.insert(Bytes::from("c"), vec![(6, Value::Tombstone)]);
+        map.insert(Bytes::from("e"), vec![(7, data("new"))]);
+        let map = Arc::new(map);
+
+        let build = |lower: Bound<Bytes>, upper: Bound<Bytes>| {
+            Iter::new(
+                vec![
+                    Box::new(TableMapIter::new(map.clone(), lower.clone(), upper.clone())),
+                    Box::new(table.range(lower, upper)),
+                ],
+                u64::MAX,
+                None,
+            )
+        };
+
+        let all = collect(build(Bound::Unbounded, Bound::Unbounded));
+        let expected: BTreeMap<Bytes, Bytes> =
+            [("a", "old"), ("b", "new"), ("d", "old"), ("e", "new")]
+                .into_iter()
+                .map(|(k, v)| (Bytes::from(k), Bytes::from(v)))
+                .collect();
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+
+        let rev = collect(build(Bound::Unbounded, Bound::Unbounded).rev());
+        assert_eq!(rev, expected.clone().into_iter().rev().collect::<Vec<_>>());
+
+        let mut iter = build(
+            Bound::Included(Bytes::from("b")),
+            Bound::Excluded(Bytes::from("e")),
+        );
+        assert_eq!(iter.next().unwrap()?.0, Bytes::from("b"));
+        assert_eq!(iter.next_back().unwrap()?.0, Bytes::from("d"));
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+        Ok(())
+    }
+}
@@ -1,96 +1,261 @@
 use crate::{
     Error,
-    constants::{WAL_CAP_LIMIT, WAL_HEADER_SIZE},
+    batch::WriteBatch,
+    checksum::Crc32c,
+    constants::{LEN_SIZE, TREE_ID_SIZE, WAL_CAP_LIMIT, WAL_HEADER_SIZE},
     mem_table::MemTable,
+    options::WalRecoveryMode,
     traits::Putable,
-    utils::ensure_dir,
+    types::{FlushedSeqnos, Value, WorkerSignal},
+    utils::{create_synced, ensure_dir, link_or_copy, remove_tmp_files, sync_dir},
+    version::Version,
 };
 use std::{
+    collections::BTreeMap,
     fs::{self, File, OpenOptions},
-    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    io::{self, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
 pub(crate) struct FileId(u64);
 
+/// End of the log at some point, to roll back to.
+pub(crate) struct WalMark {
+    id: u64,
+    len: usize,
+}
+
 pub(crate) struct Journal {
     id: FileId,
     wal: BufWriter<File>,
     wal_dir_path: PathBuf,
     bytes_written: usize,
-    sync_count: usize,
+    /// Max seqno of each tree in each WAL file, the active one included.
+    files: BTreeMap<u64, BTreeMap<u32, u64>>,
+    flushed: FlushedSeqnos,
 }
 
 impl Journal {
-    pub(crate) fn open(path: &Path, seqno: u64) -> crate::Result<(Self, MemTable, u64)> {
+    /// Replays the WAL into one MemTable per tree.
+    /// Corrupted records are handled as `mode` says.
+    /// WAL files are deleted once `flushed` shows all their records in SSTs.
+    pub(crate) fn open(
+        path: &Path,
+        version: &Version,
+        mode: WalRecoveryMode,
+        flushed: FlushedSeqnos,
+    ) -> crate::Result<(Self, BTreeMap<u32, MemTable>, u64)> {
         let wal_dir_path = path.join("wal");
-        ensure_dir(&wal_dir_path).map_err(|e| Error::Io(e))?;
-        let (mem, next_seqno, latest_id) = recovery_wal(&wal_dir_path, seqno)?;
+        ensure_dir(&wal_dir_path).map_err(Error::Io)?;
+        remove_tmp_files(&wal_dir_path)?;
+        let mut files = BTreeMap::new();
+        let (mems, next_seqno, latest_id) = recovery_wal(&wal_dir_path, version, mode, &mut files)?;
         let next_id = latest_id.0 + 1;
-        let wal = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(create_wal_path(&wal_dir_path, next_id))?;
-
-        Ok((
-            Self {
-                id: FileId(next_id),
-                wal: BufWriter::new(wal),
-                wal_dir_path,
-                bytes_written: 0,
-                sync_count: 0,
-            },
-            mem,
-            next_seqno,
-        ))
-    }
-
-    pub(crate) fn insert(&mut self, seqno: u64, key: &[u8], val: Option<&[u8]>) -> io::Result<()> {
-        let key_len = key.len() as u32;
-        let (val_len, t) = match val {
-            None => (0, 1),
-            Some(v) => (v.len() as u32, 0),
+        let wal = create_synced(&create_wal_path(&wal_dir_path, next_id))?;
+
+        let mut journal = Self {
+            id: FileId(next_id),
+            wal: BufWriter::new(wal),
+            wal_dir_path,
+            bytes_written: 0,
+            files,
+            flushed,
         };
+        journal.purge_obsolete()?;
+        Ok((journal, mems, next_seqno))
+    }
 
-        self.wal.write_all(&seqno.to_le_bytes())?;
-        self.wal.write_all(&[t])?;
-        self.wal.write_all(&key_len.to_le_bytes())?;
-        self.wal.write_all(&val_len.to_le_bytes())?;
-        self.wal.write_all(key)?;
-
-        if let Some(v) = val {
-            self.wal.write_all(v)?;
+    /// Records stay buffered until `flush`, so a group of them
+    /// can share one write and one fsync.
+    pub(crate) fn insert(
+        &mut self,
+        seqno: u64,
+        tree: u32,
+        key: &[u8],
+        val: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let (t, val) = match val {
+            None => (1, &[][..]),
+            Some(v) => (0, v),
         };
 
+        self.note(tree, seqno);
+        // key(key_len) + val(val_len)
+        self.append(seqno, t, tree, [key.len(), val.len()], &[key, val])
+    }
+
+    /// Batch record
+    ///     - header with count(4) and body_len(4) as key_len and val_len
+    ///     - body(body_len)
+    ///     - tree is unused, every entry names its own
+    ///
+    /// Body entry, seqno is the batch seqno plus the entry position
+    ///     - type(1) + tree(4) + key_len(4) + val_len(4) + key(?) + val(?)
+    ///     - type and val follow `Value::encode`
+    ///     - entries without a tree belong to `default_tree`
+    pub(crate) fn insert_batch(
+        &mut self,
+        seqno: u64,
+        default_tree: u32,
+        batch: &WriteBatch,
+    ) -> io::Result<()> {
+        let mut body: Vec<u8> = Vec::new();
+
+        for (entry_seqno, (tree, key, val)) in (seqno..).zip(batch.ops.iter()) {
+            self.note(tree.unwrap_or(default_tree), entry_seqno);
+            let (t, val) = val.encode();
+            let val = val.as_deref().unwrap_or_default();
+            body.push(t);
+            body.extend_from_slice(&tree.unwrap_or(default_tree).to_le_bytes());
+            body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(val.len() as u32).to_le_bytes());
+            body.extend_from_slice(key);
+            body.extend_from_slice(val);
+        }
+
+        self.append(seqno, 2, default_tree, [batch.len(), body.len()], &[&body])
+    }
+
+    fn note(&mut self, tree: u32, seqno: u64) {
+        let max = self
+            .files
+            .entry(self.id.0)
+            .or_default()
+            .entry(tree)
+            .or_default();
+        *max = (*max).max(seqno);
+    }
+
+    /// Record header
+    ///     - crc(4) + seqno(8) + type(1) + tree(4) + key_len(4) + val_len(4)
+    ///     - crc is the CRC32C of the rest of the header and the payload
+    fn append(
+        &mut self,
+        seqno: u64,
+        t: u8,
+        tree: u32,
+        lens: [usize; 2],
+        payload: &[&[u8]],
+    ) -> io::Result<()> {
+        let mut header = [0u8; WAL_HEADER_SIZE];
+        header[4..12].copy_from_slice(&seqno.to_le_bytes());
+        header[12] = t;
+        header[13..17].copy_from_slice(&tree.to_le_bytes());
+        header[17..21].copy_from_slice(&(lens[0] as u32).to_le_bytes());
+        header[21..25].copy_from_slice(&(lens[1] as u32).to_le_bytes());
+
+        let mut crc = Crc32c::new();
+        crc.update(&header[LEN_SIZE..]);
+        payload.iter().for_each(|part| crc.update(part));
+        header[..LEN_SIZE].copy_from_slice(&crc.finish().to_le_bytes());
+
+        self.wal.write_all(&header)?;
+        for part in payload.iter() {
+            self.wal.write_all(part)?;
+        }
+        self.bytes_written += WAL_HEADER_SIZE + payload.iter().map(|p| p.len()).sum::<usize>();
+        Ok(())
+    }
+
+    /// Links every rotated WAL file into `wal_dir_path` and copies the
+    /// active one. The caller holds the journal lock, so no record is torn.
+    pub(crate) fn copy_to(&mut self, wal_dir_path: &Path) -> io::Result<()> {
         self.wal.flush()?;
+        ensure_dir(wal_dir_path)?;
 
-        // seqno(8) + type(1) + key_len(4) + val_len(4) + key(?) + val(?)
-        let written = WAL_HEADER_SIZE + (key_len + val_len) as usize;
-        self.bytes_written += written;
-        self.sync_count += written;
+        for entry in fs::read_dir(&self.wal_dir_path)? {
+            let src = entry?.path();
+            let Some(id) = parse_file_name(&src) else {
+                continue;
+            };
+            let dst = create_wal_path(wal_dir_path, id.0);
 
+            if id.0 == self.id.0 {
+                fs::copy(&src, &dst)?;
+            } else {
+                link_or_copy(&src, &dst)?;
+            }
+            File::open(&dst)?.sync_all()?;
+        }
+        sync_dir(wal_dir_path)
+    }
+
+    /// Rotates a full WAL file, then returns the end of the log.
+    /// Writers take a mark before appending, so the records of one
+    /// group never span two files.
+    pub(crate) fn mark(&mut self) -> io::Result<WalMark> {
         if self.bytes_written >= WAL_CAP_LIMIT {
             self.rotate()?;
         }
+        Ok(WalMark {
+            id: self.id.0,
+            len: self.bytes_written,
+        })
+    }
 
+    /// Drops every record appended since `mark`, buffered or written.
+    pub(crate) fn rollback(&mut self, mark: WalMark) -> io::Result<()> {
+        debug_assert_eq!(mark.id, self.id.0);
+        let file = self.wal.get_ref().try_clone()?;
+        let (file, _discarded) =
+            std::mem::replace(&mut self.wal, BufWriter::new(file)).into_parts();
+        file.set_len(mark.len as u64)?;
+        self.bytes_written = mark.len;
         Ok(())
     }
 
+    // NOTE:
+    // Syncers only ever fsync the active file,
+    // so the file being left behind is synced here.
     fn rotate(&mut self) -> io::Result<()> {
-        if self.sync_count >= WAL_CAP_LIMIT * 4 {
-            self.fsync()?;
-            self.sync_count = 0;
-        }
+        self.flush(true)?;
         let id = self.id.0 + 1;
-        let wal = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(create_wal_path(&self.wal_dir_path, id))?;
+        let wal = create_synced(&create_wal_path(&self.wal_dir_path, id))?;
 
         self.id = FileId(id);
         self.wal = BufWriter::new(wal);
         self.bytes_written = 0;
 
+        self.purge_obsolete()
+    }
+
+    /// Deletes every inactive WAL file whose records are all in SSTs.
+    fn purge_obsolete(&mut self) -> io::Result<()> {
+        // A poisoned map only delays the deletion.
+        let Ok(flushed) = self.flushed.lock().map(|f| f.clone()) else {
+            return Ok(());
+        };
+        let obsolete: Vec<u64> = self
+            .files
+            .iter()
+            .filter(|(id, _)| **id != self.id.0)
+            .filter(|(_, trees)| {
+                trees
+                    .iter()
+                    .all(|(tree, max)| flushed.get(tree).is_some_and(|f| max < f))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in obsolete {
+            fs::remove_file(create_wal_path(&self.wal_dir_path, id))?;
+            self.files.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Writes out buffered records, then fsyncs the active WAL file if `sync`.
+    pub(crate) fn flush(&mut self, sync: bool) -> io::Result<()> {
+        self.wal.flush()?;
+        if sync {
+            self.fsync()?;
+        }
         Ok(())
     }
 
@@ -100,70 +265,256 @@ impl Journal {
     }
 }
 
+/// Fsyncs the WAL every `interval` until shut down.
+pub(crate) struct WalSyncer {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl WalSyncer {
+    pub(crate) fn start(
+        journal: Arc<Mutex<Journal>>,
+        interval: Duration,
+        err_tx: Sender<WorkerSignal>,
+    ) -> Self {
+        let (stop, stop_rx) = channel::<()>();
+
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let Ok(mut journal) = journal.lock() else {
+                    break;
+                };
+                if let Err(e) = journal.flush(true) {
+                    let _ = err_tx.send(WorkerSignal::Panic(Error::Io(e)));
+                    break;
+                }
+            }
+        });
+
+        Self { stop, handle }
+    }
+
+    /// Joins the syncer. The caller must not hold the journal lock.
+    pub(crate) fn shutdown(self) -> crate::Result<()> {
+        let _ = self.stop.send(());
+        self.handle.join().map_err(|_| Error::Poisoned)
+    }
+}
+
 fn create_wal_path(path: &Path, id: u64) -> PathBuf {
     path.join(format!("wal-{:06}.log", id))
 }
 
+// NOTE:
+// Trees flush independently, so a record is replayed only if its seqno
+// is at or above the first unflushed seqno of its own tree.
 fn recovery_wal(
     wal_dir_path: &Path,
-    next_wal_seqno: u64,
-) -> crate::Result<(MemTable, u64, FileId)> {
-    let table = MemTable::new();
-    let mut max_seqno = next_wal_seqno;
+    version: &Version,
+    mode: WalRecoveryMode,
+    files: &mut BTreeMap<u64, BTreeMap<u32, u64>>,
+) -> crate::Result<(BTreeMap<u32, MemTable>, u64, FileId)> {
+    let mut tables: BTreeMap<u32, MemTable> = BTreeMap::new();
+    let mut max_seqno = version.next_seqno;
     let mut entries: Vec<_> = fs::read_dir(wal_dir_path)?
         .filter_map(|read| read.ok())
+        .filter_map(|e| Some((parse_file_name(&e.path())?.0, e.path())))
         .collect();
-    entries.sort_by_key(|e| e.path());
-    let latest_id = entries
-        .last()
-        .and_then(|e| parse_file_name(&e.path()))
-        .unwrap_or(FileId(0));
+    entries.sort_by_key(|(id, _)| *id);
+    let latest_id = FileId(entries.last().map_or(0, |(id, _)| *id));
     // NOTE:
     // WAL recovery must replay files in ascending order.
     // Reverse replay breaks record-level ordering guarantees.
-    for entry in entries.iter() {
-        let file_path = entry.path();
-        let file = File::open(file_path)?;
-        let mut reader = BufReader::with_capacity(64 * 1024, file);
+    for (idx, (id, file_path)) in entries.iter().enumerate() {
+        let file_len = fs::metadata(file_path)?.len();
+        let mut reader = BufReader::with_capacity(64 * 1024, File::open(file_path)?);
+        let mut offset = 0;
+        let seen = files.entry(*id).or_default();
 
-        // seqno(8) + type(1) + key_len(4) + val_len(4)
         loop {
-            let mut header = [0u8; WAL_HEADER_SIZE];
+            let (seqno, t, tree, key_len, payload) =
+                match read_record(&mut reader, offset, file_len)? {
+                    ReadRecord::Eof => break,
+                    ReadRecord::Record {
+                        seqno,
+                        t,
+                        tree,
+                        key_len,
+                        payload,
+                    } => (seqno, t, tree, key_len, payload),
+                    ReadRecord::Corrupted { end } => {
+                        // NOTE:
+                        // A record cut short anywhere, header or payload, is what
+                        // a crash during an unsynced append leaves behind. It is
+                        // torn only in the newest file; older files were synced.
+                        let at_tail = end >= file_len;
+                        let torn = at_tail && idx + 1 == entries.len();
+
+                        match mode {
+                            WalRecoveryMode::AbsoluteConsistency => {}
+                            _ if torn => {
+                                truncate(file_path, offset)?;
+                                break;
+                            }
+                            WalRecoveryMode::PointInTime => {
+                                truncate(file_path, offset)?;
+                                for (_, later) in entries[idx + 1..].iter() {
+                                    fs::remove_file(later)?;
+                                }
+                                return Ok((tables, max_seqno + 1, latest_id));
+                            }
+                            WalRecoveryMode::SkipAnyCorruptedRecords if at_tail => break,
+                            WalRecoveryMode::SkipAnyCorruptedRecords => {
+                                offset = end;
+                                continue;
+                            }
+                            WalRecoveryMode::TolerateCorruptedTailRecords => {}
+                        }
+                        return Err(Error::WalCorrupted { file: *id, offset });
+                    }
+                };
+            let record_offset = offset;
+            offset += (WAL_HEADER_SIZE + payload.len()) as u64;
+
+            if t == 2 {
+                let last_seqno = replay_batch(&mut tables, seqno, &payload, version, seen)
+                    .map_err(|e| match e {
+                        Error::Corrupted => Error::WalCorrupted {
+                            file: *id,
+                            offset: record_offset,
+                        },
+                        e => e,
+                    })?;
+                max_seqno = max_seqno.max(last_seqno);
+                continue;
+            }
 
-            if let Err(e) = reader.read_exact(&mut header) {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    break;
-                }
-                return Err(e.into());
+            let max = seen.entry(tree).or_default();
+            *max = (*max).max(seqno);
+            if version.replay_from(tree) <= seqno {
+                let (key, val) = payload.split_at(key_len);
+                let val = (t == 0).then_some(val);
+
+                tables.entry(tree).or_default().put(seqno, key, val)?;
+                max_seqno = max_seqno.max(seqno);
             }
+        }
+    }
+    Ok((tables, max_seqno + 1, latest_id))
+}
 
-            let seqno = u64::from_le_bytes(header[0..8].try_into().unwrap());
-            let t = header[8];
-            let key_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
-            let val_len = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+enum ReadRecord {
+    Record {
+        seqno: u64,
+        t: u8,
+        tree: u32,
+        key_len: usize,
+        payload: Vec<u8>,
+    },
+    Eof,
+    /// Fails its checksum or is cut short by the end of the file.
+    /// `end` is where the record claims to end.
+    Corrupted {
+        end: u64,
+    },
+}
 
-            if next_wal_seqno <= seqno {
-                let mut key = vec![0u8; key_len];
-                let mut val = vec![0u8; val_len];
+/// Reads the record at `offset` of a WAL file `file_len` long.
+/// A corrupted record whose `end` is within the file has been consumed.
+fn read_record(reader: &mut BufReader<File>, offset: u64, file_len: u64) -> io::Result<ReadRecord> {
+    if offset == file_len {
+        return Ok(ReadRecord::Eof);
+    }
 
-                reader.read_exact(&mut key)?;
-                let val = match t {
-                    0 => {
-                        reader.read_exact(&mut val)?;
-                        Some(val.as_slice())
-                    }
-                    1 => None,
-                    _ => unreachable!(),
-                };
+    let mut header = [0u8; WAL_HEADER_SIZE];
+    if offset + WAL_HEADER_SIZE as u64 > file_len {
+        return Ok(ReadRecord::Corrupted { end: file_len });
+    }
+    reader.read_exact(&mut header)?;
+
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let seqno = u64::from_le_bytes(header[4..12].try_into().unwrap());
+    let t = header[12];
+    let tree = u32::from_le_bytes(header[13..17].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+    let val_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+
+    // count(4) and body_len(4) take the key_len and val_len slots.
+    let payload_len = if t == 2 { val_len } else { key_len + val_len };
+    let end = offset + (WAL_HEADER_SIZE + payload_len) as u64;
+    if end > file_len {
+        return Ok(ReadRecord::Corrupted { end });
+    }
 
-                table.put(seqno, &key, val)?;
-                max_seqno = max_seqno.max(seqno);
-            } else {
-                reader.consume(key_len + val_len);
-            }
+    let mut payload = vec![0u8; payload_len];
+    reader.read_exact(&mut payload)?;
+
+    let mut actual = Crc32c::new();
+    actual.update(&header[LEN_SIZE..]);
+    actual.update(&payload);
+    if actual.finish() != crc || t > 2 {
+        return Ok(ReadRecord::Corrupted { end });
+    }
+
+    Ok(ReadRecord::Record {
+        seqno,
+        t,
+        tree,
+        key_len,
+        payload,
+    })
+}
+
+/// Cuts a WAL file back to its last intact record.
+fn truncate(path: &Path, len: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+/// Applies every entry of a batch body that is not yet flushed
+/// and notes it in `seen`. Returns the seqno of the last entry in the batch.
+fn replay_batch(
+    tables: &mut BTreeMap<u32, MemTable>,
+    seqno: u64,
+    body: &[u8],
+    version: &Version,
+    seen: &mut BTreeMap<u32, u64>,
+) -> crate::Result<u64> {
+    let mut idx = 0;
+    let mut entry_seqno = seqno;
+
+    while idx < body.len() {
+        let header_end = idx + 1 + TREE_ID_SIZE + LEN_SIZE + LEN_SIZE;
+        if header_end > body.len() {
+            return Err(Error::Corrupted);
+        }
+
+        let t = body[idx];
+        let tree = u32::from_le_bytes(body[idx + 1..idx + 5].try_into().unwrap());
+        let key_len = u32::from_le_bytes(body[idx + 5..idx + 9].try_into().unwrap()) as usize;
+        let val_len = u32::from_le_bytes(body[idx + 9..idx + 13].try_into().unwrap()) as usize;
+        let key_end = header_end + key_len;
+        let val_end = key_end + val_len;
+        if val_end > body.len() {
+            return Err(Error::Corrupted);
+        }
+
+        let key = &body[header_end..key_end];
+        let val = Value::decode(t, &body[key_end..val_end])?;
+
+        let max = seen.entry(tree).or_default();
+        *max = (*max).max(entry_seqno);
+        if version.replay_from(tree) <= entry_seqno {
+            tables
+                .entry(tree)
+                .or_default()
+                .insert(entry_seqno, key, val)?;
         }
+        idx = val_end;
+        entry_seqno += 1;
     }
-    Ok((table, max_seqno + 1, latest_id))
+    Ok(entry_seqno.saturating_sub(1).max(seqno))
 }
 
 fn parse_file_name(path: &Path) -> Option<FileId> {
@@ -181,23 +532,71 @@ fn parse_file_name(path: &Path) -> Option<FileId> {
 mod tests {
     use crate::traits::Getable;
 
-    use bytes::Bytes;
     use super::*;
+    use crate::constants::DEFAULT_TREE;
+    use bytes::Bytes;
     use tempfile::tempdir;
 
+    fn open(path: &Path) -> crate::Result<(Journal, MemTable, u64)> {
+        open_with(path, WalRecoveryMode::default())
+    }
+
+    fn open_with(path: &Path, mode: WalRecoveryMode) -> crate::Result<(Journal, MemTable, u64)> {
+        let (journal, mut mems, next_seqno) = Journal::open(
+            path,
+            &Version::new(BTreeMap::new(), 0, 1),
+            mode,
+            Arc::default(),
+        )?;
+        let mem = mems.remove(&DEFAULT_TREE).unwrap_or_default();
+        Ok((journal, mem, next_seqno))
+    }
+
+    /// Writes a, b and c as three 27 byte records into `wal-000001.log`.
+    fn write_abc(path: &Path) -> crate::Result<PathBuf> {
+        let (mut journal, _, _) = open(path)?;
+        journal.insert(1, DEFAULT_TREE, b"a", Some(b"1"))?;
+        journal.insert(2, DEFAULT_TREE, b"b", Some(b"2"))?;
+        journal.insert(3, DEFAULT_TREE, b"c", Some(b"3"))?;
+        journal.flush(true)?;
+        Ok(create_wal_path(&path.join("wal"), 1))
+    }
+
     #[test]
     fn wal_replay() -> crate::Result<()> {
         let dir = tempdir()?;
 
         {
-            let (mut journal, _, _) = Journal::open(dir.path(), 0)?;
-            journal.insert(1, b"a", Some(b"1"))?;
-            journal.insert(2, b"b", Some(b"2"))?;
+            let (mut journal, _, _) = open(dir.path())?;
+            journal.insert(1, DEFAULT_TREE, b"a", Some(b"1"))?;
+            journal.insert(2, DEFAULT_TREE, b"b", Some(b"2"))?;
         }
 
-        let (_, mem, _) = Journal::open(dir.path(), 0)?;
-        assert_eq!(mem.get(b"a")?, Some(Bytes::from("1")));
-        assert_eq!(mem.get(b"b")?, Some(Bytes::from("2")));
+        let (_, mem, _) = open(dir.path())?;
+        assert_eq!(mem.get(b"a", u64::MAX)?, Some(Bytes::from("1")));
+        assert_eq!(mem.get(b"b", u64::MAX)?, Some(Bytes::from("2")));
+        Ok(())
+    }
+
+    #[test]
+    fn wal_replay_batch() -> crate::Result<()> {
+        let dir = tempdir()?;
+
+        {
+            let (mut journal, _, _) = open(dir.path())?;
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"1");
+            batch.put(b"b", b"2");
+            batch.remove(b"a");
+            journal.insert_batch(1, DEFAULT_TREE, &batch)?;
+            journal.insert(4, DEFAULT_TREE, b"c", Some(b"3"))?;
+        }
+
+        let (_, mem, next_seqno) = open(dir.path())?;
+        assert_eq!(mem.get(b"a", u64::MAX)?, None);
+        assert_eq!(mem.get(b"b", u64::MAX)?, Some(Bytes::from("2")));
+        assert_eq!(mem.get(b"c", u64::MAX)?, Some(Bytes::from("3")));
+        assert_eq!(next_seqno, 5);
         Ok(())
     }
 
@@ -208,11 +607,12 @@ mod tests {
         let mut rotate_cnt = 0;
 
         {
-            let (mut journal, _, _) = Journal::open(dir.path(), 0)?;
+            let (mut journal, _, _) = open(dir.path())?;
             let mut last_id = journal.id.0;
 
             while rotate_cnt < 2 {
-                journal.insert(n, b"k", Some(&[n as u8]))?;
+                journal.mark()?;
+                journal.insert(n, DEFAULT_TREE, b"k", Some(&[n as u8]))?;
                 n += 1;
 
                 if journal.id.0 != last_id {
@@ -222,12 +622,179 @@ mod tests {
             }
         }
 
-        let (_, mem, _) = Journal::open(dir.path(), 0)?;
+        let (_, mem, _) = open(dir.path())?;
         assert_eq!(
-            mem.get(b"k")?,
+            mem.get(b"k", u64::MAX)?,
             Some(Bytes::copy_from_slice(&[(n - 1) as u8]))
         );
 
         Ok(())
     }
+
+    #[test]
+    fn wal_replay_skips_flushed_records_per_tree() -> crate::Result<()> {
+        let dir = tempdir()?;
+
+        {
+            let (mut journal, _, _) = open(dir.path())?;
+            journal.insert(1, DEFAULT_TREE, b"a", Some(b"1"))?;
+            journal.insert(2, 1, b"b", Some(b"2"))?;
+            journal.insert(3, 2, b"c", Some(b"3"))?;
+        }
+
+        // Tree 1 flushed up to seqno 2, the other trees flushed nothing.
+        let mut version = Version::new(BTreeMap::new(), 3, 1);
+        version.replay_from.insert(1, 3);
+        let (_, mems, next_seqno) = Journal::open(
+            dir.path(),
+            &version,
+            WalRecoveryMode::default(),
+            Arc::default(),
+        )?;
+
+        assert_eq!(
+            mems[&DEFAULT_TREE].get(b"a", u64::MAX)?,
+            Some(Bytes::from("1"))
+        );
+        assert!(!mems.contains_key(&1));
+        assert_eq!(mems[&2].get(b"c", u64::MAX)?, Some(Bytes::from("3")));
+        assert_eq!(next_seqno, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn wal_replay_truncates_torn_tail() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let wal = write_abc(dir.path())?;
+        truncate(&wal, 27 * 2 + 10)?;
+
+        assert!(matches!(
+            open_with(dir.path(), WalRecoveryMode::AbsoluteConsistency),
+            Err(Error::WalCorrupted {
+                file: 1,
+                offset: 54
+            })
+        ));
+
+        let (_, mem, next_seqno) = open(dir.path())?;
+        assert_eq!(mem.get(b"b", u64::MAX)?, Some(Bytes::from("2")));
+        assert_eq!(mem.get(b"c", u64::MAX)?, None);
+        assert_eq!(next_seqno, 3);
+        assert_eq!(fs::metadata(&wal)?.len(), 54);
+        Ok(())
+    }
+
+    #[test]
+    fn wal_replay_truncates_record_cut_mid_payload() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let wal = write_abc(dir.path())?;
+        truncate(&wal, (27 * 2 + WAL_HEADER_SIZE + 1) as u64)?;
+
+        assert!(matches!(
+            open_with(dir.path(), WalRecoveryMode::AbsoluteConsistency),
+            Err(Error::WalCorrupted {
+                file: 1,
+                offset: 54
+            })
+        ));
+
+        let (_, mem, next_seqno) = open(dir.path())?;
+        assert_eq!(mem.get(b"b", u64::MAX)?, Some(Bytes::from("2")));
+        assert_eq!(mem.get(b"c", u64::MAX)?, None);
+        assert_eq!(next_seqno, 3);
+        assert_eq!(fs::metadata(&wal)?.len(), 54);
+        Ok(())
+    }
+
+    #[test]
+    fn wal_corruption_follows_recovery_mode() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let wal = write_abc(dir.path())?;
+        let mut data = fs::read(&wal)?;
+        data[27 + WAL_HEADER_SIZE] ^= 0xff;
+        fs::write(&wal, &data)?;
+
+        for mode in [
+            WalRecoveryMode::TolerateCorruptedTailRecords,
+            WalRecoveryMode::AbsoluteConsistency,
+        ] {
+            assert!(matches!(
+                open_with(dir.path(), mode),
+                Err(Error::WalCorrupted {
+                    file: 1,
+                    offset: 27
+                })
+            ));
+        }
+
+        let (_, mem, _) = open_with(dir.path(), WalRecoveryMode::SkipAnyCorruptedRecords)?;
+        assert_eq!(mem.get(b"a", u64::MAX)?, Some(Bytes::from("1")));
+        assert_eq!(mem.get(b"b", u64::MAX)?, None);
+        assert_eq!(mem.get(b"c", u64::MAX)?, Some(Bytes::from("3")));
+
+        let (_, mem, next_seqno) = open_with(dir.path(), WalRecoveryMode::PointInTime)?;
+        assert_eq!(mem.get(b"a", u64::MAX)?, Some(Bytes::from("1")));
+        assert_eq!(mem.get(b"c", u64::MAX)?, None);
+        assert_eq!(next_seqno, 2);
+        assert_eq!(fs::metadata(&wal)?.len(), 27);
+        Ok(())
+    }
+
+    #[test]
+    fn wal_files_are_deleted_once_flushed() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let flushed: FlushedSeqnos = Arc::default();
+        let version = Version::new(BTreeMap::new(), 0, 1);
+        let (mut journal, _, _) = Journal::open(
+            dir.path(),
+            &version,
+            WalRecoveryMode::default(),
+            flushed.clone(),
+        )?;
+        let wal = |id| create_wal_path(&dir.path().join("wal"), id);
+
+        journal.insert(1, DEFAULT_TREE, b"a", Some(b"1"))?;
+        journal.insert(2, 1, b"b", Some(b"2"))?;
+        journal.rotate()?;
+        journal.insert(3, DEFAULT_TREE, b"c", Some(b"3"))?;
+
+        // Tree 1 still needs the first file.
+        flushed.lock().unwrap().insert(DEFAULT_TREE, 4);
+        journal.rotate()?;
+        assert!(wal(1).exists());
+        assert!(!wal(2).exists());
+
+        flushed.lock().unwrap().insert(1, 3);
+        journal.rotate()?;
+        assert!(!wal(1).exists());
+        assert!(wal(journal.id.0).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_drops_buffered_and_written_records() -> crate::Result<()> {
+        let dir = tempdir()?;
+
+        {
+            let (mut journal, _, _) = open(dir.path())?;
+            journal.insert(1, DEFAULT_TREE, b"a", Some(b"1"))?;
+            journal.flush(false)?;
+
+            let mark = journal.mark()?;
+            journal.insert(2, DEFAULT_TREE, b"b", Some(b"2"))?;
+            journal.flush(false)?;
+            journal.insert(3, DEFAULT_TREE, b"c", Some(b"3"))?;
+            journal.rollback(mark)?;
+
+            journal.insert(4, DEFAULT_TREE, b"d", Some(b"4"))?;
+            journal.flush(true)?;
+        }
+
+        let (_, mem, _) = open(dir.path())?;
+        assert_eq!(mem.get(b"a", u64::MAX)?, Some(Bytes::from("1")));
+        assert_eq!(mem.get(b"b", u64::MAX)?, None);
+        assert_eq!(mem.get(b"c", u64::MAX)?, None);
+        assert_eq!(mem.get(b"d", u64::MAX)?, Some(Bytes::from("4")));
+        Ok(())
+    }
 }
@@ -1,21 +1,43 @@
+mod backup;
+mod batch;
 mod bloom;
+mod checksum;
+mod compaction;
+mod compression;
 mod constants;
 mod db;
 mod error;
 mod imm_tables;
+mod iter;
 mod journal;
+mod lock_manager;
 mod manifest;
 mod mem_table;
+mod merge;
+mod options;
+mod snapshot;
 mod sst_manager;
 mod sst_writer;
 mod sstable;
 mod table_set;
 mod traits;
+mod transaction;
+mod transaction_db;
 mod types;
 mod utils;
 mod version;
+mod write_group;
 
 pub use {
+    backup::BackupEngine,
+    batch::WriteBatch,
+    compression::{Compression, Compressor},
     db::Kepler,
-    error::{Error, Result},
+    error::{CompareAndSwapError, Error, Result},
+    iter::Iter,
+    merge::MergeOperator,
+    options::{Options, WalRecoveryMode, WriteOptions},
+    snapshot::Snapshot,
+    transaction::Transaction,
+    transaction_db::{PessimisticTransaction, TransactionDb},
 };
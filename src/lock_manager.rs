@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::Error;
+
+/// Key locks held by pessimistic transactions.
+///
+/// Each waiting transaction waits on exactly one holder, so the
+/// wait-for graph is a set of chains. A lock request that would close
+/// a cycle fails with `Error::Deadlock` instead of blocking.
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+#[derive(Default)]
+struct LockState {
+    owners: HashMap<Bytes, u64>,
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// Follows the chain of waits starting at `holder`
+    /// and reports whether it leads back to `txid`.
+    fn would_deadlock(&self, txid: u64, holder: u64) -> bool {
+        let mut next = Some(holder);
+        while let Some(id) = next {
+            if id == txid {
+                return true;
+            }
+            next = self.waits_for.get(&id).copied();
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+            next_id: AtomicU64::new(1),
+            timeout,
+        }
+    }
+
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Blocks until `txid` owns `key`.
+    /// Returns `Ok(true)` if the lock was newly taken and `Ok(false)`
+    /// if `txid` already held it.
+    pub(crate) fn lock(&self, txid: u64, key: &[u8]) -> crate::Result<bool> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+
+        loop {
+            let holder = match state.owners.get(key) {
+                None => {
+                    state.owners.insert(Bytes::copy_from_slice(key), txid);
+                    state.waits_for.remove(&txid);
+                    return Ok(true);
+                }
+                Some(owner) if *owner == txid => return Ok(false),
+                Some(owner) => *owner,
+            };
+
+            if state.would_deadlock(txid, holder) {
+                state.waits_for.remove(&txid);
+                return Err(Error::Deadlock);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&txid);
+                return Err(Error::LockTimeout);
+            }
+
+            state.waits_for.insert(txid, holder);
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .map_err(|_| Error::Poisoned)?
+                .0;
+        }
+    }
+
+    /// Releases every lock in `keys` held by `txid` and wakes the waiters.
+    ///
+    /// NOTE:
+    /// A waiter may find its key taken by another transaction on wakeup.
+    /// Its edge to `txid` is dropped here and recomputed once it wakes,
+    /// so deadlock detection never follows a stale edge meanwhile.
+    pub(crate) fn unlock_all(&self, txid: u64, keys: &[Bytes]) -> crate::Result<()> {
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+
+        for key in keys {
+            if state.owners.get(key) == Some(&txid) {
+                state.owners.remove(key);
+            }
+        }
+        state.waits_for.remove(&txid);
+        state.waits_for.retain(|_, holder| *holder != txid);
+        self.released.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn lock_times_out_while_held() -> crate::Result<()> {
+        let locks = LockManager::new(Duration::from_millis(20));
+
+        assert!(locks.lock(1, b"k")?);
+        assert!(!locks.lock(1, b"k")?);
+        assert!(matches!(locks.lock(2, b"k"), Err(Error::LockTimeout)));
+
+        locks.unlock_all(1, &[Bytes::from("k")])?;
+        assert!(locks.lock(2, b"k")?);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_cycle_is_reported_as_deadlock() -> crate::Result<()> {
+        let locks = Arc::new(LockManager::new(Duration::from_secs(5)));
+        locks.lock(1, b"a")?;
+        locks.lock(2, b"b")?;
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(2, b"a"))
+        };
+        while !locks.state.lock().unwrap().waits_for.contains_key(&2) {
+            thread::yield_now();
+        }
+
+        assert!(matches!(locks.lock(1, b"b"), Err(Error::Deadlock)));
+        locks.unlock_all(1, &[Bytes::from("a")])?;
+        assert!(waiter.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn released_holder_leaves_no_wait_edge() -> crate::Result<()> {
+        let locks = Arc::new(LockManager::new(Duration::from_secs(5)));
+        locks.lock(1, b"a")?;
+        locks.lock(2, b"c")?;
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(2, b"a"))
+        };
+        while !locks.state.lock().unwrap().waits_for.contains_key(&2) {
+            thread::yield_now();
+        }
+
+        // Whether or not the waiter has woken up yet, 1 waiting on 2
+        // closes no cycle once 1 released what 2 waited for.
+        locks.unlock_all(1, &[Bytes::from("a")])?;
+        assert!(!locks.state.lock().unwrap().would_deadlock(1, 2));
+        assert!(waiter.join().unwrap()?);
+        Ok(())
+    }
+}
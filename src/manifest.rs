@@ -1,70 +1,227 @@
 use crate::{
     Error,
-    constants::BUF_SIZE,
-    sst_writer::FlushResult,
-    types::WorkerSignal,
+    checksum::Crc32c,
+    constants::{
+        LEN_SIZE, MANIFEST_RECORD_SIZE, MANIFEST_REWRITE_SIZE, MAX_LEVELS, SEQNO_SIZE, TREE_ID_SIZE,
+    },
+    sst_writer::{FlushResult, create_sst_path, parse_sst_name},
+    types::{FlushedSeqnos, WorkerSignal},
+    utils::{read_u32, remove_tmp_files, rename_synced, tmp_path},
     version::{SSTInfo, Version},
 };
 use std::{
     collections::BTreeMap,
-    fs::OpenOptions,
-    io::{BufReader, BufWriter, Read, Write},
-    path::Path,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
     sync::{
-        Arc,
+        Arc, Mutex,
         mpsc::{Receiver, Sender, SyncSender, sync_channel},
     },
-    thread,
+    thread::{self, JoinHandle},
 };
 
+pub(crate) enum ManifestEdit {
+    Tables(Vec<FlushResult>),
+    /// Registers a named tree. `ack` fires once the record is durable.
+    CreateTree {
+        id: u32,
+        name: String,
+        ack: SyncSender<()>,
+    },
+    /// Acknowledged once every earlier edit is durable.
+    Sync(SyncSender<()>),
+    Shutdown,
+}
+
+const CURRENT: &str = "CURRENT";
+
 pub(crate) struct Manifest {
-    pub sender: SyncSender<FlushResult>,
+    pub sender: SyncSender<ManifestEdit>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    flushed: FlushedSeqnos,
 }
 
 impl Manifest {
     pub fn new(path: &Path, err_tx: Sender<WorkerSignal>) -> crate::Result<(Arc<Self>, Version)> {
-        let manifest_path = path.join("manifest");
-        let (manifest_tx, manifest_rx) = sync_channel::<FlushResult>(8);
-        let version = restore_sst_list(&manifest_path)?;
-        start_manifest_thread(&manifest_path, err_tx, manifest_rx)?;
+        let (manifest_tx, manifest_rx) = sync_channel::<ManifestEdit>(8);
+        let (file_no, live, version) = open_current(path)?;
+        let flushed = Arc::new(Mutex::new(version.replay_from.clone()));
+        let handle =
+            start_manifest_thread(path, file_no, live, flushed.clone(), err_tx, manifest_rx)?;
 
         Ok((
             Arc::new(Self {
                 sender: manifest_tx,
+                handle: Mutex::new(Some(handle)),
+                flushed,
             }),
             version,
         ))
     }
 
+    /// Per-tree flush progress, advanced once an SST edit is durable.
+    pub(crate) fn flushed(&self) -> FlushedSeqnos {
+        self.flushed.clone()
+    }
+
     pub(crate) fn send(&self, result: FlushResult) -> crate::Result<()> {
-        self.sender.send(result).map_err(|_| Error::Poisoned)?;
+        self.send_batch(vec![result])
+    }
+
+    /// Records every edit of `results` with a single fsync.
+    /// SSTs removed by the batch are deleted once the edit is durable.
+    pub(crate) fn send_batch(&self, results: Vec<FlushResult>) -> crate::Result<()> {
+        self.sender
+            .send(ManifestEdit::Tables(results))
+            .map_err(|_| Error::Poisoned)?;
+        Ok(())
+    }
+
+    /// Records `name` as tree `id` and waits until the record is durable.
+    pub(crate) fn create_tree(&self, id: u32, name: &str) -> crate::Result<()> {
+        let (ack_tx, ack_rx) = sync_channel::<()>(1);
+        self.sender
+            .send(ManifestEdit::CreateTree {
+                id,
+                name: name.to_string(),
+                ack: ack_tx,
+            })
+            .map_err(|_| Error::Poisoned)?;
+        ack_rx.recv().map_err(|_| Error::Poisoned)
+    }
+
+    /// Blocks until every edit sent so far is durable.
+    pub(crate) fn sync(&self) -> crate::Result<()> {
+        let (ack_tx, ack_rx) = sync_channel::<()>(1);
+        self.sender
+            .send(ManifestEdit::Sync(ack_tx))
+            .map_err(|_| Error::Poisoned)?;
+        ack_rx.recv().map_err(|_| Error::Poisoned)
+    }
+
+    /// Waits until every queued edit is durable, then joins the writer.
+    pub(crate) fn shutdown(&self) -> crate::Result<()> {
+        let handle = self.handle.lock().map_err(|_| Error::Poisoned)?.take();
+        if let Some(handle) = handle {
+            // A dead writer has already reported its error.
+            let _ = self.sender.send(ManifestEdit::Shutdown);
+            handle.join().map_err(|_| Error::Poisoned)?;
+        }
         Ok(())
     }
 }
 
+/// Trees, live SSTs and counters, enough to rewrite the manifest from scratch.
+pub(crate) struct LiveSet {
+    pub(crate) trees: BTreeMap<String, u32>,
+    pub(crate) tables: BTreeMap<u64, FlushResult>,
+    /// First seqno of each tree not yet in an SST. Kept apart from
+    /// `tables`, since compaction may drop a tree's newest versions.
+    pub(crate) replay_from: BTreeMap<u32, u64>,
+    pub(crate) next_seqno: u64,
+    pub(crate) next_sstno: u64,
+}
+
+impl Default for LiveSet {
+    fn default() -> Self {
+        Self {
+            trees: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            replay_from: BTreeMap::new(),
+            next_seqno: 1,
+            next_sstno: 1,
+        }
+    }
+}
+
+impl LiveSet {
+    pub(crate) fn apply(&mut self, result: &FlushResult) {
+        self.next_sstno = self.next_sstno.max(result.sstno + 1);
+        if result.t != 0 {
+            self.tables.remove(&result.sstno);
+            return;
+        }
+
+        self.note_flushed(result.tree, result.max_seqno + 1);
+        self.tables.insert(result.sstno, result.clone());
+    }
+
+    /// Records that every seqno of `tree` below `from` is in an SST.
+    pub(crate) fn note_flushed(&mut self, tree: u32, from: u64) {
+        let flushed = self.replay_from.entry(tree).or_default();
+        *flushed = (*flushed).max(from);
+        self.next_seqno = self.next_seqno.max(from);
+    }
+}
+
+// NOTE:
+// Once more than `MANIFEST_REWRITE_SIZE` bytes are appended, the live set
+// goes into a fresh manifest and CURRENT is renamed over to point at it,
+// so recovery never reads more than one rewrite worth of history.
 fn start_manifest_thread(
-    manifest_path: &Path,
+    dir: &Path,
+    mut file_no: u64,
+    mut live: LiveSet,
+    flushed: FlushedSeqnos,
     err_tx: Sender<WorkerSignal>,
-    manifest_rx: Receiver<FlushResult>,
-) -> crate::Result<()> {
-    let manifest = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .append(true)
-        .open(manifest_path)?;
-
-    thread::spawn(move || {
+    manifest_rx: Receiver<ManifestEdit>,
+) -> crate::Result<JoinHandle<()>> {
+    let manifest_path = create_manifest_path(dir, file_no);
+    let manifest = OpenOptions::new().append(true).open(&manifest_path)?;
+    let mut written = manifest.metadata()?.len() as usize;
+    let dir = dir.to_path_buf();
+    let sst_dir_path = dir.join("sst");
+
+    let handle = thread::spawn(move || {
         let mut buf = BufWriter::new(manifest);
-        // type(1) + sstno(8) + max_seqno(8) + min_seqno(8)
         let mut process = || -> Result<(), std::io::Error> {
-            while let Ok(result) = manifest_rx.recv() {
-                buf.write_all(&[result.t])?;
-                buf.write_all(&result.sstno.to_le_bytes())?;
-                buf.write_all(&result.max_seqno.to_le_bytes())?;
-                buf.write_all(&result.min_seqno.to_le_bytes())?;
+            while let Ok(edit) = manifest_rx.recv() {
+                let results = match edit {
+                    ManifestEdit::Tables(results) => results,
+                    ManifestEdit::CreateTree { id, name, ack } => {
+                        written += write_tree(&mut buf, id, &name)?;
+                        buf.flush()?;
+                        buf.get_mut().sync_all()?;
+                        live.trees.insert(name, id);
+                        let _ = ack.send(());
+                        continue;
+                    }
+                    // Every earlier edit was synced when it was written.
+                    ManifestEdit::Sync(ack) => {
+                        let _ = ack.send(());
+                        continue;
+                    }
+                    ManifestEdit::Shutdown => break,
+                };
+
+                for result in results.iter() {
+                    written += write_table(&mut buf, result)?;
+                }
                 buf.flush()?;
                 buf.get_mut().sync_all()?;
+
+                if let Ok(mut flushed) = flushed.lock() {
+                    for result in results.iter().filter(|r| r.t == 0) {
+                        let from = flushed.entry(result.tree).or_default();
+                        *from = (*from).max(result.max_seqno + 1);
+                    }
+                }
+                for result in results.iter() {
+                    live.apply(result);
+                }
+                for result in results.iter().filter(|r| r.t == 1) {
+                    fs::remove_file(create_sst_path(&sst_dir_path, result.sstno))?;
+                }
+
+                if written >= MANIFEST_REWRITE_SIZE {
+                    file_no += 1;
+                    written = rewrite(&dir, file_no, &live)?;
+                    let manifest = OpenOptions::new()
+                        .append(true)
+                        .open(create_manifest_path(&dir, file_no))?;
+                    buf = BufWriter::new(manifest);
+                }
             }
             Ok(())
         };
@@ -74,45 +231,407 @@ fn start_manifest_thread(
         }
     });
 
+    Ok(handle)
+}
+
+/// Writes `live` into manifest `file_no`, points CURRENT at it and deletes
+/// the manifest it replaces. Returns the size of the new manifest.
+fn rewrite(dir: &Path, file_no: u64, live: &LiveSet) -> std::io::Result<usize> {
+    let written = write_snapshot(&create_manifest_path(dir, file_no), live)?;
+    set_current(dir, file_no)?;
+    fs::remove_file(create_manifest_path(dir, file_no - 1))?;
+    Ok(written)
+}
+
+/// Writes the first manifest of `dir`, holding only `live`,
+/// and points CURRENT at it.
+pub(crate) fn write_manifest(dir: &Path, live: &LiveSet) -> crate::Result<()> {
+    write_snapshot(&create_manifest_path(dir, 1), live)?;
+    set_current(dir, 1)?;
     Ok(())
 }
 
-fn restore_sst_list(manifest_path: &Path) -> crate::Result<Version> {
-    let file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .append(true)
-        .open(manifest_path)?;
-    let mut reader = BufReader::with_capacity(BUF_SIZE, file);
-    let mut sst_list: BTreeMap<u64, SSTInfo> = BTreeMap::new();
-    let mut max_seqno = 0;
-    let mut max_sstno = 0;
-
-    loop {
-        let mut form = [0u8; 25];
-
-        match reader.read_exact(&mut form) {
-            Ok(_) => {
-                let t = form[0];
+fn write_snapshot(manifest_path: &Path, live: &LiveSet) -> std::io::Result<usize> {
+    let tmp = tmp_path(manifest_path);
+    let mut buf = BufWriter::new(File::create(&tmp)?);
+    let mut written = write_counters(&mut buf, live.next_seqno, live.next_sstno)?;
+
+    for (name, id) in live.trees.iter() {
+        written += write_tree(&mut buf, *id, name)?;
+    }
+    for (tree, from) in live.replay_from.iter() {
+        written += write_flushed(&mut buf, *tree, *from)?;
+    }
+    for result in live.tables.values() {
+        written += write_table(&mut buf, result)?;
+    }
+
+    buf.flush()?;
+    buf.get_mut().sync_all()?;
+    rename_synced(&tmp, manifest_path)?;
+    Ok(written)
+}
+
+/// Replaces CURRENT with a file naming manifest `file_no`.
+fn set_current(dir: &Path, file_no: u64) -> std::io::Result<()> {
+    let current = dir.join(CURRENT);
+    let tmp = tmp_path(&current);
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{}", manifest_name(file_no))?;
+    file.sync_all()?;
+
+    rename_synced(&tmp, &current)
+}
+
+fn write_table(buf: &mut impl Write, result: &FlushResult) -> std::io::Result<usize> {
+    let mut payload = Vec::with_capacity(MANIFEST_RECORD_SIZE);
+    payload.push(result.t);
+    payload.extend_from_slice(&result.tree.to_le_bytes());
+    payload.push(result.level);
+    payload.extend_from_slice(&result.sstno.to_le_bytes());
+    payload.extend_from_slice(&result.max_seqno.to_le_bytes());
+    payload.extend_from_slice(&result.min_seqno.to_le_bytes());
+    write_record(buf, &payload)
+}
+
+fn write_tree(buf: &mut impl Write, id: u32, name: &str) -> std::io::Result<usize> {
+    let mut payload = Vec::with_capacity(1 + TREE_ID_SIZE + LEN_SIZE + name.len());
+    payload.push(2);
+    payload.extend_from_slice(&id.to_le_bytes());
+    payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    payload.extend_from_slice(name.as_bytes());
+    write_record(buf, &payload)
+}
+
+fn write_flushed(buf: &mut impl Write, tree: u32, from: u64) -> std::io::Result<usize> {
+    let mut payload = Vec::with_capacity(1 + TREE_ID_SIZE + SEQNO_SIZE);
+    payload.push(3);
+    payload.extend_from_slice(&tree.to_le_bytes());
+    payload.extend_from_slice(&from.to_le_bytes());
+    write_record(buf, &payload)
+}
+
+fn write_counters(
+    buf: &mut impl Write,
+    next_seqno: u64,
+    next_sstno: u64,
+) -> std::io::Result<usize> {
+    let mut payload = Vec::with_capacity(1 + TREE_ID_SIZE + SEQNO_SIZE + SEQNO_SIZE);
+    payload.push(4);
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    payload.extend_from_slice(&next_seqno.to_le_bytes());
+    payload.extend_from_slice(&next_sstno.to_le_bytes());
+    write_record(buf, &payload)
+}
+
+fn write_record(buf: &mut impl Write, payload: &[u8]) -> std::io::Result<usize> {
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut crc = Crc32c::new();
+    crc.update(&len);
+    crc.update(payload);
+
+    buf.write_all(&crc.finish().to_le_bytes())?;
+    buf.write_all(&len)?;
+    buf.write_all(payload)?;
+    Ok(LEN_SIZE + LEN_SIZE + payload.len())
+}
+
+/// Restores the manifest CURRENT names, writing an empty first one for a
+/// new database, and deletes files a crashed rewrite left behind.
+/// Fails if CURRENT is missing but SSTs are not, since an empty
+/// manifest would leave every one of them unaccounted for.
+fn open_current(dir: &Path) -> crate::Result<(u64, LiveSet, Version)> {
+    remove_tmp_files(dir)?;
+    let file_no = match fs::read_to_string(dir.join(CURRENT)) {
+        Ok(name) => parse_manifest_name(name.trim()).ok_or(Error::Corrupted)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            if has_ssts(&dir.join("sst"))? {
+                return Err(Error::Corrupted);
+            }
+            write_manifest(dir, &LiveSet::default())?;
+            1
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if parse_manifest_name(&name).is_some_and(|n| n != file_no) {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    let (live, version) = restore_sst_list(&create_manifest_path(dir, file_no))?;
+    Ok((file_no, live, version))
+}
+
+/// Manifest Format
+///
+/// Record
+///     - crc(4) + len(4) + payload(len)
+///     - crc is the CRC32C of len and payload
+///
+/// SST Payload, type 0 adds and type 1 removes a table
+///     - type(1) + tree(4) + level(1) + sstno(8) + max_seqno(8) + min_seqno(8)
+///
+/// Tree Payload, type 2
+///     - type(1) + tree(4) + name_len(4) + name(name_len)
+///
+/// Flushed Payload, type 3, written by rewrites
+///     - type(1) + tree(4) + replay_from(8)
+///     - every seqno of the tree below replay_from is in an SST,
+///       even if compaction has since dropped it
+///
+/// Counters Payload, type 4, written by rewrites
+///     - type(1) + tree(4), always 0 + next_seqno(8) + next_sstno(8)
+///
+/// NOTE:
+/// Every edit is fsynced before it takes effect, so a record cut short
+/// or failing its checksum at the end of the file was never acknowledged
+/// and is truncated away. A bad record anywhere else is corruption.
+fn restore_sst_list(manifest_path: &Path) -> crate::Result<(LiveSet, Version)> {
+    let data = fs::read(manifest_path)?;
+    let mut live = LiveSet::default();
+    let mut idx = 0;
+
+    while idx < data.len() {
+        let Some(payload) = read_record(&data, idx)? else {
+            let file = OpenOptions::new().write(true).open(manifest_path)?;
+            file.set_len(idx as u64)?;
+            file.sync_all()?;
+            break;
+        };
+        idx += LEN_SIZE + LEN_SIZE + payload.len();
+
+        let head = payload.get(..1 + TREE_ID_SIZE).ok_or(Error::Corrupted)?;
+        let t = head[0];
+        let tree = u32::from_le_bytes(head[1..].try_into().unwrap());
+        let form = &payload[1 + TREE_ID_SIZE..];
+
+        match t {
+            2 => {
+                let name_len = read_u32(form, 0).ok_or(Error::Corrupted)? as usize;
+                let name = form.get(LEN_SIZE..).ok_or(Error::Corrupted)?;
+                if name.len() != name_len {
+                    return Err(Error::Corrupted);
+                }
+                let name = String::from_utf8(name.to_vec()).map_err(|_| Error::Corrupted)?;
+                live.trees.insert(name, tree);
+            }
+            3 if form.len() == SEQNO_SIZE => {
+                live.note_flushed(tree, u64::from_le_bytes(form.try_into().unwrap()));
+            }
+            4 if form.len() == SEQNO_SIZE + SEQNO_SIZE => {
+                let next_seqno = u64::from_le_bytes(form[..8].try_into().unwrap());
+                let next_sstno = u64::from_le_bytes(form[8..].try_into().unwrap());
+                live.next_seqno = live.next_seqno.max(next_seqno);
+                live.next_sstno = live.next_sstno.max(next_sstno);
+            }
+            0 | 1 if payload.len() == MANIFEST_RECORD_SIZE => {
+                let level = form[0];
                 let sstno = u64::from_le_bytes(form[1..9].try_into().unwrap());
                 let maxno = u64::from_le_bytes(form[9..17].try_into().unwrap());
-
-                match t {
-                    0 => {
-                        max_sstno = max_sstno.max(sstno);
-                        max_seqno = max_seqno.max(maxno);
-                        sst_list.insert(sstno, SSTInfo::new(sstno));
-                    }
-                    1 => {
-                        sst_list.remove(&sstno);
-                    }
-                    _ => return Err(Error::Corrupted),
+                let minno = u64::from_le_bytes(form[17..25].try_into().unwrap());
+                if t == 0 && level as usize >= MAX_LEVELS {
+                    return Err(Error::Corrupted);
                 }
+                live.apply(&FlushResult::new(t, tree, level, sstno, maxno, minno));
             }
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-            Err(e) => return Err(Error::Io(e)),
+            _ => return Err(Error::Corrupted),
+        }
+    }
+
+    let sst_list = live
+        .tables
+        .values()
+        .map(|r| (r.sstno, SSTInfo::new(r.sstno, r.tree, r.level as usize)))
+        .collect();
+    let mut version = Version::new(sst_list, live.next_seqno, live.next_sstno);
+    version.trees = live.trees.clone();
+    version.replay_from = live.replay_from.clone();
+    Ok((live, version))
+}
+
+/// Returns the payload of the record at `idx`, or `None` for a torn
+/// record that ends the file.
+fn read_record(data: &[u8], idx: usize) -> crate::Result<Option<&[u8]>> {
+    let payload_start = idx + LEN_SIZE + LEN_SIZE;
+    let Some(header) = data.get(idx..payload_start) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(header[LEN_SIZE..].try_into().unwrap()) as usize;
+    let Some(payload) = data.get(payload_start..payload_start + len) else {
+        return Ok(None);
+    };
+
+    let mut crc = Crc32c::new();
+    crc.update(&header[LEN_SIZE..]);
+    crc.update(payload);
+    if crc.finish().to_le_bytes() == header[..LEN_SIZE] {
+        Ok(Some(payload))
+    } else if payload_start + len == data.len() {
+        Ok(None)
+    } else {
+        Err(Error::Corrupted)
+    }
+}
+
+fn has_ssts(sst_dir_path: &Path) -> std::io::Result<bool> {
+    let entries = match fs::read_dir(sst_dir_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        if parse_sst_name(&name.to_string_lossy()).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn manifest_name(file_no: u64) -> String {
+    format!("manifest-{:06}.log", file_no)
+}
+
+fn create_manifest_path(dir: &Path, file_no: u64) -> PathBuf {
+    dir.join(manifest_name(file_no))
+}
+
+fn parse_manifest_name(name: &str) -> Option<u64> {
+    name.strip_prefix("manifest-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use tempfile::tempdir;
+
+    #[test]
+    fn torn_tail_is_dropped_and_mid_file_corruption_fails() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let mut live = LiveSet::default();
+        live.apply(&FlushResult::new(0, 0, 0, 1, 10, 1));
+        live.apply(&FlushResult::new(0, 0, 0, 2, 20, 11));
+        write_manifest(dir.path(), &live)?;
+        let path = create_manifest_path(dir.path(), 1);
+        let intact = fs::read(&path)?;
+
+        fs::write(&path, &intact[..intact.len() - 3])?;
+        let (_, version) = restore_sst_list(&path)?;
+        assert_eq!(version.sst_list.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(version.replay_from(0), 21);
+        assert_eq!(
+            fs::metadata(&path)?.len() as usize,
+            intact.len() - (LEN_SIZE + LEN_SIZE + MANIFEST_RECORD_SIZE)
+        );
+
+        let mut corrupted = intact.clone();
+        corrupted[LEN_SIZE + LEN_SIZE] ^= 0xff;
+        fs::write(&path, &corrupted)?;
+        assert!(matches!(restore_sst_list(&path), Err(Error::Corrupted)));
+        Ok(())
+    }
+
+    #[test]
+    fn tree_record_with_wrong_name_len_is_corrupted() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let path = create_manifest_path(dir.path(), 1);
+
+        let mut payload = vec![2];
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&9u32.to_le_bytes());
+        payload.extend_from_slice(b"other");
+        let mut file = File::create(&path)?;
+        write_record(&mut file, &payload)?;
+        write_tree(&mut file, 2, "next")?;
+        drop(file);
+
+        assert!(matches!(restore_sst_list(&path), Err(Error::Corrupted)));
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_keeps_live_set_and_moves_current() -> crate::Result<()> {
+        let dir = tempdir()?;
+        write_manifest(dir.path(), &LiveSet::default())?;
+        fs::create_dir(dir.path().join("sst"))?;
+        fs::write(create_sst_path(&dir.path().join("sst"), 1), b"")?;
+
+        {
+            let (manifest, _) = Manifest::new(dir.path(), channel().0)?;
+            manifest.create_tree(1, "other")?;
+            manifest.send_batch(vec![
+                FlushResult::new(0, 0, 0, 1, 10, 1),
+                FlushResult::new(0, 1, 0, 2, 20, 11),
+            ])?;
+            manifest.send_batch(vec![
+                FlushResult::new(1, 0, 0, 1, 10, 1),
+                FlushResult::new(0, 0, 1, 3, 10, 1),
+            ])?;
+            manifest.shutdown()?;
+        }
+
+        let (_, live, _) = open_current(dir.path())?;
+        rewrite(dir.path(), 2, &live)?;
+        assert!(!create_manifest_path(dir.path(), 1).exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join(CURRENT))?.trim(),
+            "manifest-000002.log"
+        );
+
+        let (file_no, _, version) = open_current(dir.path())?;
+        assert_eq!(file_no, 2);
+        assert_eq!(version.trees.get("other"), Some(&1));
+        assert_eq!(version.sst_list.keys().collect::<Vec<_>>(), vec![&2, &3]);
+        assert_eq!(version.sst_list[&3].level, 1);
+        assert_eq!(version.replay_from(1), 21);
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_keeps_flush_progress_of_dropped_tables() -> crate::Result<()> {
+        let dir = tempdir()?;
+        write_manifest(dir.path(), &LiveSet::default())?;
+        fs::create_dir(dir.path().join("sst"))?;
+        for sstno in 1..=3 {
+            fs::write(create_sst_path(&dir.path().join("sst"), sstno), b"")?;
+        }
+
+        {
+            let (manifest, _) = Manifest::new(dir.path(), channel().0)?;
+            manifest.create_tree(1, "other")?;
+            // Tree 0 writes a key, then deletes it, and tree 1 flushes
+            // older writes, so a WAL file below seqno 20 stays alive.
+            manifest.send_batch(vec![
+                FlushResult::new(0, 0, 0, 1, 10, 1),
+                FlushResult::new(0, 0, 0, 2, 20, 20),
+                FlushResult::new(0, 1, 0, 3, 5, 5),
+            ])?;
+            // A bottommost compaction drops the key and its tombstone.
+            manifest.send_batch(vec![
+                FlushResult::new(1, 0, 0, 1, 10, 1),
+                FlushResult::new(1, 0, 0, 2, 20, 20),
+            ])?;
+            manifest.shutdown()?;
         }
+
+        let (_, live, _) = open_current(dir.path())?;
+        rewrite(dir.path(), 2, &live)?;
+
+        // Replaying tree 0 from below 20 would bring the key back.
+        let (_, _, version) = open_current(dir.path())?;
+        assert_eq!(version.sst_list.keys().collect::<Vec<_>>(), vec![&3]);
+        assert_eq!(version.replay_from(0), 21);
+        assert_eq!(version.replay_from(1), 6);
+        assert_eq!(version.next_seqno, 21);
+        assert_eq!(version.next_sstno, 4);
+        Ok(())
     }
-    Ok(Version::new(sst_list, max_seqno + 1, max_sstno + 1))
 }
@@ -2,11 +2,13 @@ use crate::{
     Error,
     constants::SEQNO_SIZE,
     traits::{Getable, Putable},
-    types::{TableMap, Value},
+    types::{Entry, TableMap, Value, newest_visible},
+    utils::{SliceRange, as_slice_bound, is_valid_range},
 };
 use bytes::Bytes;
 use std::{
     collections::BTreeMap,
+    ops::Bound,
     sync::{
         RwLock,
         atomic::{AtomicUsize, Ordering},
@@ -14,39 +16,24 @@ use std::{
 };
 
 impl Getable for MemTable {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        if let Some(v) = self.try_get(key)? {
-            match v {
-                Value::Data(b) => return Ok(Some(b)),
-                Value::Tombstone => return Ok(None),
-            }
-        }
-        Ok(None)
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        self.try_get(key, seqno)
     }
 }
 
 impl Putable for MemTable {
     fn put(&self, seqno: u64, key: &[u8], val: Option<&[u8]>) -> crate::Result<()> {
-        let key_bytes = Bytes::copy_from_slice(key);
-        let mut allocated = key.len() + SEQNO_SIZE;
-
         let value = match val {
-            Some(v) => {
-                allocated += v.len();
-                Value::Data(Bytes::copy_from_slice(v))
-            }
-            None => {
-                allocated += 1;
-                Value::Tombstone
-            }
+            Some(v) => Value::Data(Bytes::copy_from_slice(v)),
+            None => Value::Tombstone,
         };
+        self.insert(seqno, key, value)
+    }
+}
 
-        self.bytes_written.fetch_add(allocated, Ordering::Relaxed);
-        self.tree
-            .write()
-            .map_err(|_| Error::Poisoned)?
-            .insert(key_bytes, (seqno, value));
-        Ok(())
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -63,6 +50,25 @@ impl MemTable {
         }
     }
 
+    pub(crate) fn insert(&self, seqno: u64, key: &[u8], value: Value) -> crate::Result<()> {
+        let allocated = key.len()
+            + SEQNO_SIZE
+            + match &value {
+                Value::Data(v) | Value::Merge(v) => v.len(),
+                Value::Expiring { val, .. } => val.len() + SEQNO_SIZE,
+                Value::Tombstone => 1,
+            };
+
+        self.bytes_written.fetch_add(allocated, Ordering::Relaxed);
+        self.tree
+            .write()
+            .map_err(|_| Error::Poisoned)?
+            .entry(Bytes::copy_from_slice(key))
+            .or_default()
+            .push((seqno, value));
+        Ok(())
+    }
+
     pub fn bytes_written(&self) -> usize {
         self.bytes_written.load(Ordering::Relaxed)
     }
@@ -72,9 +78,30 @@ impl MemTable {
         Ok(std::mem::take(&mut *guard))
     }
 
-    fn try_get(&self, key: &[u8]) -> crate::Result<Option<Value>> {
+    /// Copies out the entries within bounds, so the caller never holds the lock.
+    /// Versions of a key come out newest first.
+    pub fn range(&self, lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> crate::Result<Vec<Entry>> {
+        if !is_valid_range(lower, upper) {
+            return Ok(Vec::new());
+        }
+
+        let guard = self.tree.read().map_err(|_| Error::Poisoned)?;
+        let bounds: SliceRange = (as_slice_bound(lower), as_slice_bound(upper));
+
+        Ok(guard
+            .range::<[u8], _>(bounds)
+            .flat_map(|(key, versions)| {
+                versions
+                    .iter()
+                    .rev()
+                    .map(|(seqno, val)| (key.clone(), *seqno, val.clone()))
+            })
+            .collect())
+    }
+
+    fn try_get(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
         let guard = self.tree.read().map_err(|_| Error::Poisoned)?;
-        if let Some((_seqno, val)) = guard.get(key) {
+        if let Some(val) = guard.get(key).and_then(|v| newest_visible(v, seqno)) {
             return Ok(Some(val.clone()));
         }
         Ok(None)
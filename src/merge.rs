@@ -0,0 +1,111 @@
+use bytes::Bytes;
+
+use crate::{Error, types::Value, utils::now_millis};
+
+/// Combines the operands written by `Kepler::merge` with the value
+/// they apply to. Registered once, when the database is opened.
+pub trait MergeOperator: Send + Sync {
+    /// Applies `operand` to `existing`, the value of `key` so far.
+    /// Returning `None` deletes the key.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Folds `operands`, newest first, onto `base`.
+pub(crate) fn fold(
+    merge_operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    base: Option<Bytes>,
+    operands: &[Bytes],
+) -> crate::Result<Value> {
+    let merge_operator = merge_operator.ok_or(Error::NoMergeOperator)?;
+    let mut acc = base;
+
+    for operand in operands.iter().rev() {
+        acc = merge_operator
+            .merge(key, acc.as_deref(), operand)
+            .map(Bytes::from);
+    }
+
+    Ok(match acc {
+        Some(v) => Value::Data(v),
+        None => Value::Tombstone,
+    })
+}
+
+/// Resolves the versions of a key, newest first, into what a reader sees.
+/// Operands are collected until the first data or tombstone version.
+///
+/// Expired versions read as tombstones. Returns the seqno of the newest
+/// version and either a `Value::Data` or a `Value::Tombstone`.
+pub(crate) fn resolve(
+    merge_operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    versions: impl IntoIterator<Item = crate::Result<(u64, Value)>>,
+) -> crate::Result<Option<(u64, Value)>> {
+    let mut newest: Option<u64> = None;
+    let mut operands: Vec<Bytes> = Vec::new();
+    let mut base: Option<Bytes> = None;
+
+    let now = now_millis();
+
+    for version in versions {
+        let (seqno, val) = version?;
+        newest.get_or_insert(seqno);
+
+        match val.expire(now) {
+            Value::Merge(operand) => operands.push(operand),
+            Value::Data(v) | Value::Expiring { val: v, .. } => {
+                base = Some(v);
+                break;
+            }
+            Value::Tombstone => break,
+        }
+    }
+
+    let Some(newest) = newest else {
+        return Ok(None);
+    };
+    if operands.is_empty() {
+        return Ok(Some((newest, base.map_or(Value::Tombstone, Value::Data))));
+    }
+    Ok(Some((newest, fold(merge_operator, key, base, &operands)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn merge(&self, _: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+            let mut v = existing.unwrap_or_default().to_vec();
+            v.extend_from_slice(operand);
+            Some(v)
+        }
+    }
+
+    #[test]
+    fn resolve_folds_operands_onto_the_first_base() -> crate::Result<()> {
+        let versions = vec![
+            (4, Value::Merge(Bytes::from("c"))),
+            (3, Value::Merge(Bytes::from("b"))),
+            (2, Value::Data(Bytes::from("a"))),
+            (1, Value::Data(Bytes::from("old"))),
+        ];
+
+        let resolved = resolve(Some(&Append), b"k", versions.into_iter().map(Ok))?;
+        assert!(matches!(resolved, Some((4, Value::Data(v))) if v == "abc"));
+
+        let versions = vec![(2, Value::Merge(Bytes::from("x"))), (1, Value::Tombstone)];
+        let resolved = resolve(Some(&Append), b"k", versions.into_iter().map(Ok))?;
+        assert!(matches!(resolved, Some((2, Value::Data(v))) if v == "x"));
+
+        let versions = vec![(1, Value::Merge(Bytes::from("x")))];
+        assert!(matches!(
+            resolve(None, b"k", versions.into_iter().map(Ok)),
+            Err(Error::NoMergeOperator)
+        ));
+        Ok(())
+    }
+}
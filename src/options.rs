@@ -0,0 +1,45 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{compression::Compression, merge::MergeOperator};
+
+/// Durability of a single write.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// Fsyncs the WAL before the write returns.
+    pub sync: bool,
+    /// Skips the WAL. Until its MemTable is flushed, the write is
+    /// lost on a crash and missing from checkpoints. Closing flushes it.
+    pub disable_wal: bool,
+}
+
+/// How WAL replay treats a record that fails its checksum
+/// or is cut short by the end of its file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    /// Drops a torn record at the end of the newest WAL file, as a crash
+    /// during an unsynced write leaves behind. Fails on any other corruption.
+    #[default]
+    TolerateCorruptedTailRecords,
+    /// Fails on any corruption, a torn tail included.
+    AbsoluteConsistency,
+    /// Replays up to the first corruption and discards the rest of the WAL.
+    PointInTime,
+    /// Skips corrupted records and replays everything else.
+    SkipAnyCorruptedRecords,
+}
+
+/// Settings fixed when a `Kepler` instance is opened.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Used by every write that takes no `WriteOptions`.
+    pub write_options: WriteOptions,
+    /// Fsyncs the WAL in the background this often, if set.
+    pub wal_sync_interval: Option<Duration>,
+    /// Applied to corrupted WAL records on open.
+    pub wal_recovery_mode: WalRecoveryMode,
+    /// Folds the operands written by `merge`.
+    /// The same operator must be used on every open.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Compresses the data blocks of SSTs written from now on.
+    pub compression: Compression,
+}
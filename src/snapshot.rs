@@ -0,0 +1,273 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    Error,
+    db::KeplerInner,
+    iter::Iter,
+    merge::{MergeOperator, fold},
+    table_set::TableSet,
+    types::{Value, Versions},
+    utils::{now_millis, owned_bounds, prefix_bounds},
+};
+
+/// A consistent, read-only view of a `Kepler` instance.
+///
+/// Only entries with a seqno up to the snapshot's seqno are visible.
+/// Flush and compaction keep every version a live snapshot can still see.
+pub struct Snapshot {
+    seqno: u64,
+    db: Arc<KeplerInner>,
+    tables: Arc<TableSet>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(db: Arc<KeplerInner>, tables: Arc<TableSet>) -> crate::Result<Self> {
+        let seqno = db.pin_snapshot()?;
+        Ok(Self { seqno, db, tables })
+    }
+
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        self.db.get_at(&self.tables, key, self.seqno)
+    }
+
+    pub fn iter(&self) -> crate::Result<Iter> {
+        self.db
+            .range_at(&self.tables, Bound::Unbounded, Bound::Unbounded, self.seqno)
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> crate::Result<Iter> {
+        let (lower, upper) = owned_bounds(range);
+        self.db.range_at(&self.tables, lower, upper, self.seqno)
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> crate::Result<Iter> {
+        let (lower, upper) = prefix_bounds(prefix);
+        self.db.range_at(&self.tables, lower, upper, self.seqno)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = self.db.snapshots.release(self.seqno);
+    }
+}
+
+/// Seqnos pinned by live snapshots, with a count per seqno.
+pub(crate) struct SnapshotList(Mutex<BTreeMap<u64, usize>>);
+
+impl SnapshotList {
+    pub(crate) fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    pub(crate) fn acquire(&self, seqno: u64) -> crate::Result<()> {
+        let mut list = self.0.lock().map_err(|_| Error::Poisoned)?;
+        *list.entry(seqno).or_insert(0) += 1;
+        Ok(())
+    }
+
+    fn release(&self, seqno: u64) -> crate::Result<()> {
+        let mut list = self.0.lock().map_err(|_| Error::Poisoned)?;
+        if let Some(count) = list.get_mut(&seqno) {
+            *count -= 1;
+            if *count == 0 {
+                list.remove(&seqno);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the pinned seqnos in ascending order.
+    pub(crate) fn seqnos(&self) -> crate::Result<Vec<u64>> {
+        Ok(self
+            .0
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .keys()
+            .copied()
+            .collect())
+    }
+}
+
+/// Decides which versions survive a flush or compaction.
+///
+/// Snapshots split the versions of a key into stripes, and readers only
+/// ever see the newest version of each stripe. Merge operands on top of
+/// a stripe are folded into the first data or tombstone below them in
+/// the same stripe; operands with nothing to fold onto are kept as is.
+/// Expired values are written as tombstones.
+pub(crate) struct VersionGc {
+    snapshots: Vec<u64>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl VersionGc {
+    pub(crate) fn new(snapshots: Vec<u64>, merge_operator: Option<Arc<dyn MergeOperator>>) -> Self {
+        Self {
+            snapshots,
+            merge_operator,
+        }
+    }
+
+    /// Takes every version of `key`, newest first,
+    /// and returns the ones to write, newest first.
+    pub(crate) fn collapse(
+        &self,
+        key: &[u8],
+        versions: Vec<(u64, Value)>,
+    ) -> crate::Result<Versions> {
+        let mut kept: Versions = Vec::new();
+        let mut operands: Vec<(u64, Bytes)> = Vec::new();
+        let mut stripe: Option<usize> = None;
+        let mut resolved = false;
+        let now = now_millis();
+
+        for (seqno, val) in versions {
+            let val = val.expire(now);
+            // The first snapshot at or above `seqno` bounds its stripe.
+            let s = self.snapshots.partition_point(|snap| *snap < seqno);
+            if stripe != Some(s) {
+                kept.extend(operands.drain(..).map(|(s, op)| (s, Value::Merge(op))));
+                stripe = Some(s);
+                resolved = false;
+            }
+            if resolved {
+                continue;
+            }
+
+            match val {
+                Value::Merge(op) => operands.push((seqno, op)),
+                base => {
+                    resolved = true;
+                    kept.extend(self.fold(key, &mut operands, seqno, base)?);
+                }
+            }
+        }
+
+        kept.extend(operands.into_iter().map(|(s, op)| (s, Value::Merge(op))));
+        Ok(kept)
+    }
+
+    fn fold(
+        &self,
+        key: &[u8],
+        operands: &mut Vec<(u64, Bytes)>,
+        seqno: u64,
+        base: Value,
+    ) -> crate::Result<Versions> {
+        let Some(&(newest, _)) = operands.first() else {
+            return Ok(vec![(seqno, base)]);
+        };
+
+        // NOTE:
+        // Without an operator the stripe is written unfolded,
+        // so reopening without one never fails a flush. Neither is
+        // a stripe on an expiring base folded: once the base expires,
+        // its operands apply to nothing, as they do on read.
+        if self.merge_operator.is_none() || matches!(base, Value::Expiring { .. }) {
+            let mut unfolded: Versions = operands
+                .drain(..)
+                .map(|(s, op)| (s, Value::Merge(op)))
+                .collect();
+            unfolded.push((seqno, base));
+            return Ok(unfolded);
+        }
+
+        let base = match base {
+            Value::Data(v) | Value::Expiring { val: v, .. } => Some(v),
+            _ => None,
+        };
+        let ops: Vec<Bytes> = operands.drain(..).map(|(_, op)| op).collect();
+        let val = fold(self.merge_operator.as_deref(), key, base, &ops)?;
+        Ok(vec![(newest, val)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqnos(versions: &Versions) -> Vec<u64> {
+        versions.iter().map(|(s, _)| *s).collect()
+    }
+
+    fn data(v: &str) -> Value {
+        Value::Data(Bytes::from(v.to_string()))
+    }
+
+    #[test]
+    fn gc_keeps_versions_pinned_by_snapshots() -> crate::Result<()> {
+        let gc = VersionGc::new(vec![5], None);
+        let versions = vec![
+            (10, data("d")),
+            (7, data("c")),
+            (4, data("b")),
+            (2, data("a")),
+        ];
+
+        assert_eq!(seqnos(&gc.collapse(b"k", versions)?), vec![10, 4]);
+        assert_eq!(seqnos(&gc.collapse(b"z", vec![(1, data("a"))])?), vec![1]);
+        Ok(())
+    }
+
+    struct Concat;
+
+    impl MergeOperator for Concat {
+        fn merge(&self, _: &[u8], old: Option<&[u8]>, op: &[u8]) -> Option<Vec<u8>> {
+            Some([old.unwrap_or_default(), op].concat())
+        }
+    }
+
+    #[test]
+    fn gc_folds_merge_operands_per_stripe() -> crate::Result<()> {
+        let gc = VersionGc::new(vec![5], Some(Arc::new(Concat)));
+        let versions = vec![
+            (9, Value::Merge(Bytes::from("d"))),
+            (8, Value::Merge(Bytes::from("c"))),
+            (4, Value::Merge(Bytes::from("b"))),
+            (3, data("a")),
+            (1, data("old")),
+        ];
+
+        let kept = gc.collapse(b"k", versions)?;
+        assert_eq!(seqnos(&kept), vec![9, 8, 4]);
+        assert!(matches!(&kept[2].1, Value::Data(v) if v == "ab"));
+        assert!(matches!(&kept[0].1, Value::Merge(v) if v == "d"));
+        Ok(())
+    }
+
+    #[test]
+    fn gc_keeps_operands_on_an_expiring_base_unfolded() -> crate::Result<()> {
+        let gc = VersionGc::new(Vec::new(), Some(Arc::new(Concat)));
+        let expires_at = now_millis() + 60_000;
+        let versions = vec![
+            (3, Value::Merge(Bytes::from("b"))),
+            (
+                2,
+                Value::Expiring {
+                    val: Bytes::from("a"),
+                    expires_at,
+                },
+            ),
+            (1, data("old")),
+        ];
+
+        let kept = gc.collapse(b"k", versions)?;
+        assert_eq!(seqnos(&kept), vec![3, 2]);
+        assert!(matches!(&kept[0].1, Value::Merge(v) if v == "b"));
+        assert!(
+            matches!(&kept[1].1, Value::Expiring { val, expires_at: e } if val == "a" && *e == expires_at)
+        );
+        Ok(())
+    }
+}
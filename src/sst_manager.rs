@@ -2,8 +2,9 @@ use bytes::Bytes;
 use memmap2::Mmap;
 use std::{
     fs::{self, File},
-    io::{Read, Seek, SeekFrom},
-    path::Path,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    ops::Bound,
+    path::{Path, PathBuf},
     sync::{
         Arc, RwLock,
         atomic::{AtomicU64, Ordering},
@@ -13,30 +14,49 @@ use std::{
 use crate::{
     Error,
     bloom::BloomFilter,
-    constants::{LEN_SIZE, MAGIC, OFFSET_SIZE},
-    sstable::{SSTable, SparseIndex},
+    compression::Compression,
+    constants::{LEN_SIZE, MAGIC, MAX_LEVELS},
+    sst_writer::{create_sst_path, parse_sst_name},
+    sstable::{SSTIter, SSTMeta, SSTable, checked, decode_index},
     traits::Getable,
-    utils::{ensure_dir, from_le_to_u32, from_le_to_u64},
+    types::Value,
+    utils::{ensure_dir, read_u32, remove_tmp_files, sync_dir},
+    version::Version,
 };
 
 impl Getable for SSTManager {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        self.lookup_latest(key)
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        self.lookup_latest(key, seqno)
     }
 }
 
+/// `levels[0]` holds flushed tables in flush order and may overlap.
+/// Every deeper level is sorted by smallest key and never overlaps.
+///
+/// Every tree has its own manager. They share the SST directory and
+/// draw sstnos from one counter, so file names never collide.
 pub struct SSTManager {
-    tables: RwLock<Vec<Arc<SSTable>>>,
-    id: AtomicU64,
+    pub(crate) tree: u32,
+    levels: RwLock<Vec<Vec<Arc<SSTable>>>>,
+    id: Arc<AtomicU64>,
+    pub(crate) compression: Compression,
 }
 
 impl SSTManager {
-    pub(crate) fn open(path: &Path, next_sstno: u64) -> crate::Result<Self> {
-        let tables = recovery_sst(path)?;
+    pub(crate) fn open(
+        path: &Path,
+        version: &Version,
+        tree: u32,
+        id: Arc<AtomicU64>,
+        compression: Compression,
+    ) -> crate::Result<Self> {
+        let levels = recovery_sst(path, version, tree, &compression)?;
 
         Ok(Self {
-            tables: RwLock::new(tables),
-            id: AtomicU64::new(next_sstno),
+            tree,
+            levels: RwLock::new(levels),
+            id,
+            compression,
         })
     }
 
@@ -45,95 +65,218 @@ impl SSTManager {
     }
 
     pub(crate) fn push(&self, table: SSTable) -> crate::Result<()> {
-        self.tables
-            .write()
-            .map_err(|_| Error::Concurrency)?
-            .push(Arc::new(table));
+        self.levels.write().map_err(|_| Error::Concurrency)?[0].push(Arc::new(table));
 
         Ok(())
     }
 
-    fn lookup_latest(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        let tables = &self.tables.read().map_err(|_| Error::Concurrency)?;
+    pub(crate) fn levels(&self) -> crate::Result<Vec<Vec<Arc<SSTable>>>> {
+        Ok(self.levels.read().map_err(|_| Error::Concurrency)?.clone())
+    }
+
+    /// Swaps compaction inputs for their outputs in a single step,
+    /// so readers observe either the old or the new table set.
+    pub(crate) fn apply_compaction(
+        &self,
+        removed: &[u64],
+        added: Vec<SSTable>,
+    ) -> crate::Result<()> {
+        let mut levels = self.levels.write().map_err(|_| Error::Concurrency)?;
+
+        for level in levels.iter_mut() {
+            level.retain(|t| !removed.contains(&t.id));
+        }
+        for table in added {
+            levels[table.level].push(Arc::new(table));
+        }
+        for level in levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+        }
+        Ok(())
+    }
+
+    /// Returns iterators over every table overlapping the bounds, newest first.
+    pub(crate) fn range(
+        &self,
+        lower: &Bound<Bytes>,
+        upper: &Bound<Bytes>,
+    ) -> crate::Result<Vec<SSTIter>> {
+        let levels = &self.levels.read().map_err(|_| Error::Concurrency)?;
+        let l0 = levels[0].iter().rev();
+        let deeper = levels.iter().skip(1).flatten();
+
+        Ok(l0
+            .chain(deeper)
+            .filter(|t| t.overlaps_bounds(lower, upper))
+            .map(|t| t.range(lower.clone(), upper.clone()))
+            .collect())
+    }
+
+    /// Stops at the first table holding a visible version of `key`,
+    /// even if that version is a tombstone.
+    fn lookup_latest(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        let levels = &self.levels.read().map_err(|_| Error::Concurrency)?;
+
+        for table in levels[0].iter().rev() {
+            if table.contains(key)
+                && let Some(v) = table.lookup(key, seqno)?
+            {
+                return Ok(Some(v));
+            }
+        }
 
-        for table in tables.iter().rev() {
-            if table.contains(key) {
-                if let Ok(Some(v)) = table.get(key) {
-                    return Ok(Some(v));
-                }
+        for level in levels.iter().skip(1) {
+            let i = level.partition_point(|t| t.largest() < key);
+            if let Some(table) = level.get(i)
+                && table.smallest() <= key
+                && table.contains(key)
+                && let Some(v) = table.lookup(key, seqno)?
+            {
+                return Ok(Some(v));
             }
         }
         Ok(None)
     }
 }
 
-fn recovery_sst(path: &Path) -> crate::Result<Vec<Arc<SSTable>>> {
-    let mut tables: Vec<Arc<SSTable>> = Vec::new();
+fn recovery_sst(
+    path: &Path,
+    version: &Version,
+    tree: u32,
+    compression: &Compression,
+) -> crate::Result<Vec<Vec<Arc<SSTable>>>> {
+    let mut levels: Vec<Vec<Arc<SSTable>>> = vec![Vec::new(); MAX_LEVELS];
     let sst_dir_path = path.join("sst");
     ensure_dir(&sst_dir_path)?;
 
-    let mut entries: Vec<_> = fs::read_dir(&sst_dir_path)?
-        .filter_map(|read| read.ok())
-        .collect();
-    entries.sort_by_key(|e| e.path());
-
-    for entry in entries {
-        let file_path = entry.path();
-        let mut file = File::open(file_path)?;
+    // NOTE:
+    // Only tables recorded as live in the manifest are opened.
+    // A compaction input that was removed from the manifest may still be
+    // on disk, and loading it would shadow newer data in deeper levels.
+    for (sstno, info) in version.sst_list.iter().filter(|(_, i)| i.tree == tree) {
+        let corrupted = Error::SstCorrupted { sstno: *sstno };
+        let file_path = create_sst_path(&sst_dir_path, *sstno);
+        let mut file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(corrupted),
+            Err(e) => return Err(e.into()),
+        };
 
         let mut footer = [0u8; 48];
+        let file_len = file.metadata()?.len();
+        if file_len < footer.len() as u64 {
+            return Err(corrupted);
+        }
         file.seek(SeekFrom::End(-48))?;
         file.read_exact(&mut footer)?;
 
-        if u64::from_le_bytes(footer[40..48].try_into().unwrap()) != MAGIC {
-            return Err(Error::Corrupted);
+        if u64::from_le_bytes(footer[40..48].try_into().unwrap()) != MAGIC
+            || u64::from_le_bytes(footer[32..40].try_into().unwrap()) != *sstno
+        {
+            return Err(corrupted);
         }
 
         // Footer
-        //      -sparse_idx_offset(8) + bloom_filter_offset(8)
+        //      -index_offset(8) + bloom_filter_offset(8)
         //          + max_seqno(8) + min_seqno(8) + sstno(8)
         //          + magic_number(8)
         //
         let mmap = unsafe { Mmap::map(&file)? };
-        let sparse_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap()) as usize;
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap()) as usize;
         let bloom_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap()) as usize;
-        let sstno = u64::from_le_bytes(footer[32..40].try_into().unwrap());
+        let max_seqno = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        let min_seqno = u64::from_le_bytes(footer[24..32].try_into().unwrap());
+        let sstno = *sstno;
+        let footer_offset = mmap.len() - footer.len();
 
-        let index = sparse_idx_from_offset(sparse_offset, &mmap)?;
-        let bloomfilter = bloom_filter_from_offset(bloom_offset, &mmap)?;
+        // The index and the bloom filter each end in a CRC32C trailer.
+        let index = bloom_offset
+            .checked_sub(index_offset + LEN_SIZE)
+            .and_then(|len| checked(&mmap, index_offset, len))
+            .and_then(decode_index);
+        let bloomfilter = footer_offset
+            .checked_sub(bloom_offset + LEN_SIZE)
+            .and_then(|len| checked(&mmap, bloom_offset, len))
+            .and_then(bloom_filter_from);
+        let (Some(index), Some(bloomfilter)) = (index, bloomfilter) else {
+            return Err(corrupted);
+        };
+        let meta = SSTMeta {
+            id: sstno,
+            level: info.level,
+            max_seqno,
+            min_seqno,
+        };
 
-        tables.push(Arc::new(SSTable::new(sstno, mmap, index, bloomfilter)));
+        let table = SSTable::new(meta, mmap, index, bloomfilter, compression.clone())?;
+        levels[info.level].push(Arc::new(table));
     }
-    Ok(tables)
+
+    for level in levels.iter_mut().skip(1) {
+        level.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+    }
+    Ok(levels)
 }
 
-fn sparse_idx_from_offset(offset: usize, mmap: &Mmap) -> crate::Result<Vec<SparseIndex>> {
-    let mut sparse_index: Vec<SparseIndex> = Vec::new();
-    let mut idx_count = from_le_to_u32(mmap, offset, 0, LEN_SIZE)?;
-    let mut idx = offset + LEN_SIZE;
+/// Clears `sst/` of every SST file the manifest does not list as live,
+/// and of temp files. Must run before any tree opens its SSTs.
+///
+/// NOTE:
+/// A table numbered at or above the recorded `next_sstno` was written
+/// by a flush or compaction that crashed before the manifest saw it,
+/// so it is deleted. Any other orphan is moved into `lost/` rather than
+/// deleted, so a manifest that lost track of live tables costs disk
+/// space instead of data.
+pub(crate) fn move_orphans(path: &Path, version: &Version) -> crate::Result<()> {
+    let sst_dir_path = path.join("sst");
+    let lost_dir_path = path.join("lost");
+    ensure_dir(&sst_dir_path)?;
+    remove_tmp_files(&sst_dir_path)?;
+
+    let (mut removed, mut moved) = (false, false);
+    for entry in fs::read_dir(&sst_dir_path)? {
+        let file_path = entry?.path();
+        let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        let Some(sstno) = parse_sst_name(&name) else {
+            continue;
+        };
+        if version.sst_list.contains_key(&sstno) {
+            continue;
+        }
 
-    while idx_count > 0 {
-        let key_len = from_le_to_u32(mmap, idx, 0, LEN_SIZE)? as usize;
-        let key_start = idx + LEN_SIZE;
-        let key_end = key_start + key_len;
+        if sstno >= version.next_sstno {
+            fs::remove_file(&file_path)?;
+            removed = true;
+        } else {
+            ensure_dir(&lost_dir_path)?;
+            fs::rename(&file_path, lost_path(&lost_dir_path, &name))?;
+            moved = true;
+        }
+    }
 
-        let key = &mmap[key_start..key_end];
-        let key_block_offset = from_le_to_u64(mmap, key_end, 0, OFFSET_SIZE)? as usize;
-        let block_len = from_le_to_u64(mmap, key_end + OFFSET_SIZE, 0, OFFSET_SIZE)? as usize;
+    if removed || moved {
+        sync_dir(&sst_dir_path)?;
+    }
+    if moved {
+        sync_dir(&lost_dir_path)?;
+    }
+    Ok(())
+}
 
-        let new_idx = SparseIndex::new(key, key_block_offset, block_len);
-        sparse_index.push(new_idx);
-        idx += LEN_SIZE + key_len + OFFSET_SIZE + OFFSET_SIZE;
-        idx_count -= 1;
+/// A path in `lost/` for `name` that no earlier orphan holds.
+fn lost_path(lost_dir_path: &Path, name: &str) -> PathBuf {
+    let mut path = lost_dir_path.join(name);
+    let mut copy = 0;
+    while path.exists() {
+        copy += 1;
+        path = lost_dir_path.join(format!("{name}.{copy}"));
     }
-    Ok(sparse_index)
+    path
 }
 
-fn bloom_filter_from_offset(offset: usize, mmap: &Mmap) -> crate::Result<BloomFilter> {
-    let filter_len = from_le_to_u32(mmap, offset, 0, LEN_SIZE)? as usize;
-    let bit_size = from_le_to_u32(mmap, offset + LEN_SIZE, 0, LEN_SIZE)? as usize;
-    let filter_start = offset + LEN_SIZE + LEN_SIZE;
-    let filter_end = filter_start + filter_len;
-    let bloom_filter: Vec<u8> = mmap[filter_start..filter_end].to_vec();
-    Ok(BloomFilter::options(bloom_filter, bit_size))
+fn bloom_filter_from(data: &[u8]) -> Option<BloomFilter> {
+    let filter_len = read_u32(data, 0)? as usize;
+    let bit_size = read_u32(data, LEN_SIZE)? as usize;
+    let filter = data.get(LEN_SIZE * 2..LEN_SIZE * 2 + filter_len)?;
+    Some(BloomFilter::options(filter.to_vec(), bit_size))
 }
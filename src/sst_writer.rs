@@ -1,38 +1,57 @@
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io::{BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
-        Arc,
+        Arc, Mutex,
         mpsc::{Receiver, Sender, SyncSender, sync_channel},
     },
-    thread,
+    thread::{self, JoinHandle},
 };
 
+use bytes::Bytes;
 use memmap2::Mmap;
 
 use crate::{
     Error,
-    bloom::BloomFilter,
-    constants::{BUF_SIZE, LEN_SIZE, MAGIC, OFFSET_SIZE, PAGE_4KB},
+    bloom::{BloomFilter, hash_key_split},
+    checksum::crc32c,
+    compaction::Compactor,
+    compression::Compression,
+    constants::{LEN_SIZE, MAGIC, PAGE_4KB, RESTART_INTERVAL},
     imm_tables::ImmTables,
     manifest::Manifest,
+    merge::MergeOperator,
+    snapshot::{SnapshotList, VersionGc},
     sst_manager::SSTManager,
-    sstable::{SSTable, SparseIndex},
-    types::{TableMap, Value, WorkerSignal},
+    sstable::{ENTRY_MIN_SIZE, SSTMeta, SSTable, SparseIndex, encode_index},
+    types::{TableMap, Value, WorkerSignal, stop_worker},
+    utils::{rename_synced, tmp_path},
 };
 
+#[derive(Clone)]
 pub struct FlushResult {
     pub t: u8,
+    pub tree: u32,
+    pub level: u8,
     pub sstno: u64,
     pub max_seqno: u64,
     pub min_seqno: u64,
 }
 
 impl FlushResult {
-    fn new(t: u8, sstno: u64, max_seqno: u64, min_seqno: u64) -> Self {
+    pub(crate) fn new(
+        t: u8,
+        tree: u32,
+        level: u8,
+        sstno: u64,
+        max_seqno: u64,
+        min_seqno: u64,
+    ) -> Self {
         Self {
             t,
+            tree,
+            level,
             sstno,
             max_seqno,
             min_seqno,
@@ -42,169 +61,359 @@ impl FlushResult {
 
 pub(crate) struct SSTWriter {
     sender: SyncSender<WorkerSignal>,
+    handle: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl SSTWriter {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         path: &Path,
         manifest: Arc<Manifest>,
         imm_tables: Arc<ImmTables>,
         sst_manager: Arc<SSTManager>,
+        compactor: Compactor,
+        snapshots: Arc<SnapshotList>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
         err_tx: Sender<WorkerSignal>,
     ) -> crate::Result<Self> {
         let (flush_tx, flush_rx) = sync_channel::<WorkerSignal>(4);
 
-        start_sst_writer_thread(path, manifest, imm_tables, sst_manager, flush_rx, err_tx)?;
-
-        Ok(Self { sender: flush_tx })
+        let handle = start_sst_writer_thread(
+            path,
+            manifest,
+            imm_tables,
+            sst_manager,
+            compactor,
+            snapshots,
+            merge_operator,
+            flush_rx,
+            err_tx,
+        )?;
+
+        Ok(Self {
+            sender: flush_tx,
+            handle: Mutex::new(Some(handle)),
+        })
     }
 
     pub(crate) fn send(&self, signal: WorkerSignal) -> crate::Result<()> {
         self.sender.send(signal).map_err(|_| Error::Poisoned)?;
         Ok(())
     }
+
+    /// Waits for every queued flush, then joins the writer and its compactor.
+    pub(crate) fn shutdown(&self) -> crate::Result<()> {
+        let handle = self.handle.lock().map_err(|_| Error::Poisoned)?.take();
+        handle.map_or(Ok(()), |handle| stop_worker(&self.sender, handle))
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_sst_writer_thread(
     path: &Path,
     manifest: Arc<Manifest>,
     imm_tables: Arc<ImmTables>,
     sst_manager: Arc<SSTManager>,
+    compactor: Compactor,
+    snapshots: Arc<SnapshotList>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
     flush_rx: Receiver<WorkerSignal>,
     err_tx: Sender<WorkerSignal>,
-) -> crate::Result<()> {
+) -> crate::Result<JoinHandle<()>> {
     let sst_dir_path = path.join("sst");
 
-    thread::spawn(move || {
+    let handle = thread::spawn(move || {
         let process = || -> crate::Result<()> {
-            while let Ok(WorkerSignal::Flush(table_map)) = flush_rx.recv() {
+            loop {
+                let table_map = match flush_rx.recv() {
+                    Ok(WorkerSignal::Flush(table_map)) => table_map,
+                    Ok(WorkerSignal::Sync(ack)) => {
+                        manifest.sync()?;
+                        let _ = ack.send(());
+                        continue;
+                    }
+                    _ => break,
+                };
                 let sstno = sst_manager.get_id();
+                let gc = VersionGc::new(snapshots.seqnos()?, merge_operator.clone());
 
-                let (sstable, result) = flush_one(&sst_dir_path, sstno, table_map)?;
+                let (sstable, result) =
+                    flush_one(&sst_dir_path, &sst_manager, sstno, table_map, gc)?;
                 sst_manager.push(sstable)?;
                 manifest.send(result)?;
                 imm_tables.pop_front()?;
+                compactor.notify();
             }
             Ok(())
         };
 
-        if let Err(_) = process() {
-            let _ = err_tx.send(WorkerSignal::Panic(Error::Poisoned));
+        // NOTE:
+        // Flushes are queued ahead of `Shutdown`, so every ImmTable
+        // is written before the loop ends and the compactor is stopped.
+        if let Err(e) = process().and(compactor.shutdown()) {
+            let _ = err_tx.send(WorkerSignal::Panic(e));
         }
     });
 
-    Ok(())
+    Ok(handle)
 }
 
+/// Writes an SST under a temp name and publishes it in `finish`.
+///
 /// SST Format
 ///
-/// Data Block
-///     - Values(?)
+/// Data Block, cut between distinct keys once about `PAGE_4KB` long
+///     - contents + codec(1) + crc(4)
+///     - contents are compressed by the codec, or stored as is with codec 0
+///     - crc is the CRC32C of the stored contents and codec, checked on every read
+///
+/// Data Block Contents
+///     - entries + [restart_offset(4)] + restart_count(4)
+///     - every `RESTART_INTERVAL`th entry, the first included, is a restart
+///       point storing its whole key, so a lookup can binary search them
 ///
-/// Sparse Index
-///     - index_count(4) + key_len(4) + key(key_len) + key_block_offset(8)
-///         + block_len(8)
+/// Data Block Entry
+///     - shared_len(4) + unshared_len(4) + key_suffix(unshared_len) + seqno(8)
+///         + value_type(1) + val_len(4) + val(val_len)
+///     - the key is the first shared_len bytes of the previous key
+///       followed by key_suffix, shared_len is 0 at restart points
+///     - value_type and val follow `Value::encode`, tombstones have no val
+///     - versions of a key are ordered newest first and never span two blocks
 ///
-/// Key Block
-///     - key_len(4) + key(key_len) + val_block_offset(8)
+/// Index Block
+///     - index_count(4) + [key_len(4) + first_key(key_len) + block_offset(8)
+///         + block_len(8)] + crc(4)
+///     - block_len includes the codec but not the crc
 ///
 /// Bloom filter
-///     - filter_len(4) + bit_size(4) + BloomFilter(filter_len)
+///     - filter_len(4) + bit_size(4) + BloomFilter(filter_len) + crc(4)
 ///
 /// Footer
-///     - sparse_idx_offset(8) + bloom_filter_offset(8) + max_seqno(8)
+///     - index_offset(8) + bloom_filter_offset(8) + max_seqno(8)
 ///         + min_seqno(8) + sstno(8) + magic_number(8)
-fn flush_one(
-    sst_path: &Path,
+pub(crate) struct SSTBuilder {
+    path: PathBuf,
+    tree: u32,
     sstno: u64,
-    table_map: Arc<TableMap>,
-) -> crate::Result<(SSTable, FlushResult)> {
-    let sst_path = sst_path.join(format!("sst-{:06}.log", sstno));
-    let sst = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&sst_path)?;
-
-    let mut buf = BufWriter::new(&sst);
-    let mut buf_2: Vec<u8> = Vec::with_capacity(BUF_SIZE);
-
-    let mut filter = BloomFilter::new(table_map.len());
-    let mut sparse_index: Vec<SparseIndex> = Vec::new();
-    let mut index_set: Vec<(&[u8], usize)> = Vec::new();
-
-    let mut sparse_key = None;
-    let mut key_block_idx = LEN_SIZE;
-    let mut block_len = 0;
-    let mut val_offset = 0;
-    let (mut max_seqno, mut min_seqno) = (0, u64::MAX);
-
-    for (key, (seqno, val)) in table_map.iter() {
-        max_seqno = max_seqno.max(*seqno);
-        min_seqno = min_seqno.min(*seqno);
-
-        let val: &[u8] = match val {
-            Value::Data(b) => b.as_ref(),
-            Value::Tombstone => &[],
-        };
+    level: usize,
+    buf: BufWriter<File>,
+    block: Vec<u8>,
+    block_key: Option<Bytes>,
+    block_entries: usize,
+    restarts: Vec<u32>,
+    last_key: Option<Bytes>,
+    key_hashes: Vec<(u64, u64)>,
+    index: Vec<SparseIndex>,
+    compression: Compression,
+    offset: usize,
+    max_seqno: u64,
+    min_seqno: u64,
+}
 
-        if sparse_key.is_none() {
-            sparse_key = Some(key);
-        }
+impl SSTBuilder {
+    pub(crate) fn new(
+        sst_dir_path: &Path,
+        tree: u32,
+        sstno: u64,
+        level: usize,
+        compression: Compression,
+    ) -> crate::Result<Self> {
+        let path = create_sst_path(sst_dir_path, sstno);
+        let sst = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(tmp_path(&path))?;
+
+        Ok(Self {
+            path,
+            tree,
+            sstno,
+            level,
+            buf: BufWriter::new(sst),
+            block: Vec::with_capacity(PAGE_4KB),
+            block_key: None,
+            block_entries: 0,
+            restarts: Vec::new(),
+            last_key: None,
+            key_hashes: Vec::new(),
+            index: Vec::new(),
+            compression,
+            offset: 0,
+            max_seqno: 0,
+            min_seqno: u64::MAX,
+        })
+    }
 
-        let key_len = key.len();
-        let val_len = val.len();
+    pub(crate) fn add(&mut self, key: &Bytes, seqno: u64, val: &Value) -> crate::Result<()> {
+        self.max_seqno = self.max_seqno.max(seqno);
+        self.min_seqno = self.min_seqno.min(seqno);
 
-        buf.write_all(val)?;
-        buf_2.write_all(&(key_len as u32).to_le_bytes())?;
-        buf_2.write_all(key)?;
-        buf_2.write_all(&(val_offset as u64).to_le_bytes())?;
-        filter.add(key);
+        let (t, val) = val.encode();
+        let val = val.as_deref().unwrap_or_default();
 
-        val_offset += val_len;
-        block_len += LEN_SIZE + key_len + OFFSET_SIZE;
+        // Cut the block only between distinct keys, so a lookup
+        // finds every version of a key in a single block.
+        let new_key = self.last_key.as_ref() != Some(key);
+        if new_key && self.block.len() + ENTRY_MIN_SIZE >= PAGE_4KB {
+            self.finish_block()?;
+        }
+
+        if self.block_key.is_none() {
+            self.block_key = Some(key.clone());
+        }
 
-        if block_len + LEN_SIZE + OFFSET_SIZE >= PAGE_4KB {
-            if let Some(s_key) = sparse_key {
-                key_block_idx += LEN_SIZE + s_key.len() + OFFSET_SIZE;
-                index_set.push((s_key, block_len));
-                block_len = 0;
+        // Restart points store the whole key, every other entry
+        // only what follows the prefix shared with the previous key.
+        let shared = match &self.last_key {
+            Some(prev) if !self.block_entries.is_multiple_of(RESTART_INTERVAL) => prev
+                .iter()
+                .zip(key.iter())
+                .take_while(|(a, b)| a == b)
+                .count(),
+            _ => {
+                self.restarts.push(self.block.len() as u32);
+                0
             }
-            sparse_key = None;
+        };
+        self.block_entries += 1;
+
+        self.block.extend_from_slice(&(shared as u32).to_le_bytes());
+        self.block
+            .extend_from_slice(&((key.len() - shared) as u32).to_le_bytes());
+        self.block.extend_from_slice(&key[shared..]);
+        self.block.extend_from_slice(&seqno.to_le_bytes());
+        self.block.push(t);
+        self.block
+            .extend_from_slice(&(val.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(val);
+        if new_key {
+            self.key_hashes.push(hash_key_split(key));
+            self.last_key = Some(key.clone());
         }
+        Ok(())
     }
 
-    key_block_idx += val_offset;
-    buf.write_all(&(index_set.len() as u32).to_le_bytes())?;
+    pub(crate) fn is_empty(&self) -> bool {
+        self.key_hashes.is_empty()
+    }
+
+    /// Bytes written so far, plus the pending block uncompressed.
+    pub(crate) fn estimated_size(&self) -> usize {
+        self.offset + self.block.len()
+    }
 
-    for idx in index_set {
-        buf.write_all(&(idx.0.len() as u32).to_le_bytes())?;
-        buf.write_all(idx.0)?;
-        buf.write_all(&(key_block_idx as u64).to_le_bytes())?;
-        buf.write_all(&(idx.1 as u64).to_le_bytes())?;
+    fn finish_block(&mut self) -> std::io::Result<()> {
+        let Some(first_key) = self.block_key.take() else {
+            return Ok(());
+        };
 
-        let new_idx = SparseIndex::new(idx.0, key_block_idx, idx.1);
-        sparse_index.push(new_idx);
-        key_block_idx += idx.1;
+        let restart_count = self.restarts.len() as u32;
+        for restart in self.restarts.drain(..) {
+            self.block.extend_from_slice(&restart.to_le_bytes());
+        }
+        self.block.extend_from_slice(&restart_count.to_le_bytes());
+        self.block_entries = 0;
+
+        let (id, data) = self.compression.compress(&self.block);
+        let mut stored = data.into_owned();
+        stored.push(id);
+
+        self.buf.write_all(&stored)?;
+        self.buf.write_all(&crc32c(&stored).to_le_bytes())?;
+        self.index
+            .push(SparseIndex::new(&first_key, self.offset, stored.len()));
+        self.offset += stored.len() + LEN_SIZE;
+        self.block.clear();
+        Ok(())
     }
 
-    buf.write_all(&buf_2)?;
-    buf.write_all(&(filter.len() as u32).to_le_bytes())?;
-    buf.write_all(&(filter.bit_size() as u32).to_le_bytes())?;
-    buf.write_all(filter.as_slice())?;
+    pub(crate) fn finish(mut self) -> crate::Result<(SSTable, FlushResult)> {
+        self.finish_block()?;
 
-    buf.write_all(&(val_offset as u64).to_le_bytes())?;
-    buf.write_all(&(key_block_idx as u64).to_le_bytes())?;
-    buf.write_all(&max_seqno.to_le_bytes())?;
-    buf.write_all(&min_seqno.to_le_bytes())?;
-    buf.write_all(&sstno.to_le_bytes())?;
-    buf.write_all(&MAGIC.to_le_bytes())?;
+        let index_offset = self.offset;
+        let index = encode_index(&self.index);
+        self.buf.write_all(&index)?;
+        self.buf.write_all(&crc32c(&index).to_le_bytes())?;
 
-    buf.flush()?;
-    buf.get_mut().sync_all()?;
+        let mut filter = BloomFilter::new(self.key_hashes.len());
+        for hash in self.key_hashes.drain(..) {
+            filter.add(hash);
+        }
+
+        let bloom_offset = index_offset + index.len() + LEN_SIZE;
+        let mut bloom = Vec::with_capacity(LEN_SIZE + LEN_SIZE + filter.len());
+        bloom.extend_from_slice(&(filter.len() as u32).to_le_bytes());
+        bloom.extend_from_slice(&(filter.bit_size() as u32).to_le_bytes());
+        bloom.extend_from_slice(filter.as_slice());
+        self.buf.write_all(&bloom)?;
+        self.buf.write_all(&crc32c(&bloom).to_le_bytes())?;
+
+        self.buf.write_all(&(index_offset as u64).to_le_bytes())?;
+        self.buf.write_all(&(bloom_offset as u64).to_le_bytes())?;
+        self.buf.write_all(&self.max_seqno.to_le_bytes())?;
+        self.buf.write_all(&self.min_seqno.to_le_bytes())?;
+        self.buf.write_all(&self.sstno.to_le_bytes())?;
+        self.buf.write_all(&MAGIC.to_le_bytes())?;
+
+        let sst = self.buf.into_inner().map_err(|e| e.into_error())?;
+        sst.sync_all()?;
+        rename_synced(&tmp_path(&self.path), &self.path)?;
+
+        let mmap = unsafe { Mmap::map(&sst)? };
+        let meta = SSTMeta {
+            id: self.sstno,
+            level: self.level,
+            max_seqno: self.max_seqno,
+            min_seqno: self.min_seqno,
+        };
+        let sstable = SSTable::new(meta, mmap, self.index, filter, self.compression)?;
+        let result = FlushResult::new(
+            0,
+            self.tree,
+            self.level as u8,
+            self.sstno,
+            self.max_seqno,
+            self.min_seqno,
+        );
+
+        Ok((sstable, result))
+    }
+}
 
-    let mmap = unsafe { Mmap::map(&sst)? };
-    let sstable = SSTable::new(sstno, mmap, sparse_index, filter);
-    let result = FlushResult::new(0, sstno, max_seqno, min_seqno);
+pub(crate) fn create_sst_path(sst_dir_path: &Path, sstno: u64) -> PathBuf {
+    sst_dir_path.join(format!("sst-{:06}.log", sstno))
+}
+
+pub(crate) fn parse_sst_name(name: &str) -> Option<u64> {
+    name.strip_prefix("sst-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+fn flush_one(
+    sst_dir_path: &Path,
+    sst_manager: &SSTManager,
+    sstno: u64,
+    table_map: Arc<TableMap>,
+    gc: VersionGc,
+) -> crate::Result<(SSTable, FlushResult)> {
+    let mut builder = SSTBuilder::new(
+        sst_dir_path,
+        sst_manager.tree,
+        sstno,
+        0,
+        sst_manager.compression.clone(),
+    )?;
+
+    for (key, versions) in table_map.iter() {
+        let newest_first = versions.iter().rev().cloned().collect();
+        for (seqno, val) in gc.collapse(key, newest_first)? {
+            builder.add(key, seqno, &val)?;
+        }
+    }
 
-    Ok((sstable, result))
+    builder.finish()
 }
@@ -1,11 +1,20 @@
 use crate::{
+    Error,
     bloom::BloomFilter,
-    constants::{LEN_SIZE, OFFSET_SIZE},
+    checksum::crc32c,
+    compression::Compression,
+    constants::{LEN_SIZE, OFFSET_SIZE, SEQNO_SIZE, VALUE_TYPE_SIZE},
     traits::Getable,
-    utils::{from_le_to_u32, from_le_to_u64},
+    types::{Entry, Value},
+    utils::{in_bounds, read_u32, read_u64},
 };
 use bytes::Bytes;
 use memmap2::Mmap;
+use std::{borrow::Cow, collections::VecDeque, ops::Bound, sync::Arc};
+
+// shared_len(4) + unshared_len(4) + seqno(8) + value_type(1) + val_len(4)
+pub(crate) const ENTRY_MIN_SIZE: usize =
+    LEN_SIZE + LEN_SIZE + SEQNO_SIZE + VALUE_TYPE_SIZE + LEN_SIZE;
 
 pub(crate) struct SparseIndex {
     first_key: Bytes,
@@ -23,84 +32,489 @@ impl SparseIndex {
     }
 }
 
+/// Index block contents, without the crc.
+pub(crate) fn encode_index(index: &[SparseIndex]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(index.len() as u32).to_le_bytes());
+
+    for entry in index.iter() {
+        buf.extend_from_slice(&(entry.first_key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.first_key);
+        buf.extend_from_slice(&(entry.offset as u64).to_le_bytes());
+        buf.extend_from_slice(&(entry.len as u64).to_le_bytes());
+    }
+    buf
+}
+
+pub(crate) fn decode_index(data: &[u8]) -> Option<Vec<SparseIndex>> {
+    let count = read_u32(data, 0)?;
+    let mut index = Vec::with_capacity(count as usize);
+    let mut idx = LEN_SIZE;
+
+    for _ in 0..count {
+        let key_len = read_u32(data, idx)? as usize;
+        let key_end = idx + LEN_SIZE + key_len;
+        let key = data.get(idx + LEN_SIZE..key_end)?;
+        let offset = read_u64(data, key_end)? as usize;
+        let len = read_u64(data, key_end + OFFSET_SIZE)? as usize;
+
+        index.push(SparseIndex::new(key, offset, len));
+        idx = key_end + OFFSET_SIZE + OFFSET_SIZE;
+    }
+    Some(index)
+}
+
+/// Returns the `len` bytes at `offset` if the CRC32C trailer after them matches.
+pub(crate) fn checked(data: &[u8], offset: usize, len: usize) -> Option<&[u8]> {
+    let body = data.get(offset..offset.checked_add(len)?)?;
+    let trailer = data.get(offset + len..offset + len + LEN_SIZE)?;
+    (crc32c(body).to_le_bytes() == trailer).then_some(body)
+}
+
+/// A decoded data block entry. Its key is the cursor's current key.
+struct BlockEntry<'a> {
+    seqno: u64,
+    t: u8,
+    val: &'a [u8],
+}
+
+/// Walks the entries of a decompressed data block in order,
+/// rebuilding each key from the prefix it shares with the previous one.
+struct BlockCursor<'a> {
+    entries: &'a [u8],
+    restarts: &'a [u8],
+    idx: usize,
+    key: Vec<u8>,
+}
+
+impl<'a> BlockCursor<'a> {
+    fn new(block: &'a [u8]) -> Option<Self> {
+        let count_idx = block.len().checked_sub(LEN_SIZE)?;
+        let count = read_u32(block, count_idx)? as usize;
+        let restarts_idx = count_idx.checked_sub(count.checked_mul(LEN_SIZE)?)?;
+        if count == 0 {
+            return None;
+        }
+
+        Some(Self {
+            entries: &block[..restarts_idx],
+            restarts: &block[restarts_idx..count_idx],
+            idx: 0,
+            key: Vec::new(),
+        })
+    }
+
+    fn restart_count(&self) -> usize {
+        self.restarts.len() / LEN_SIZE
+    }
+
+    /// Full key of the entry at restart point `i`, which shares no prefix.
+    fn restart_key(&self, i: usize) -> Option<&'a [u8]> {
+        let idx = read_u32(self.restarts, i * LEN_SIZE)? as usize;
+        let start = idx + LEN_SIZE + LEN_SIZE;
+        let len = read_u32(self.entries, idx + LEN_SIZE)? as usize;
+        self.entries.get(start..start.checked_add(len)?)
+    }
+
+    fn seek_restart(&mut self, i: usize) -> Option<()> {
+        self.idx = read_u32(self.restarts, i * LEN_SIZE)? as usize;
+        self.key.clear();
+        Some(())
+    }
+
+    fn is_done(&self) -> bool {
+        self.idx >= self.entries.len()
+    }
+
+    fn next_entry(&mut self) -> Option<BlockEntry<'a>> {
+        let data = self.entries;
+        let shared = read_u32(data, self.idx)? as usize;
+        let key_start = self.idx + LEN_SIZE + LEN_SIZE;
+        let key_end = key_start.checked_add(read_u32(data, self.idx + LEN_SIZE)? as usize)?;
+        if shared > self.key.len() {
+            return None;
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(data.get(key_start..key_end)?);
+
+        let seqno = read_u64(data, key_end)?;
+        let type_idx = key_end + SEQNO_SIZE;
+        let t = *data.get(type_idx)?;
+        let val_start = type_idx + VALUE_TYPE_SIZE + LEN_SIZE;
+        let val_end = val_start.checked_add(read_u32(data, type_idx + VALUE_TYPE_SIZE)? as usize)?;
+        let val = data.get(val_start..val_end)?;
+        self.idx = val_end;
+
+        Some(BlockEntry { seqno, t, val })
+    }
+}
+
 impl Getable for SSTable {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
         let i = self.index.partition_point(|x| x.first_key <= key);
         if i == 0 {
             return Ok(None);
         }
 
-        let target = &self.index[i - 1];
-        match self.search(key, target.offset, target.len)? {
-            Some(b) => Ok(Some(b)),
-            None => Ok(None),
-        }
+        // Every version of a key lives in the same block.
+        self.search(key, seqno, i - 1)
     }
 }
 
 pub(crate) struct SSTable {
-    #[allow(dead_code)]
     pub(crate) id: u64,
+    pub(crate) level: usize,
+    pub(crate) max_seqno: u64,
+    pub(crate) min_seqno: u64,
+    smallest: Bytes,
+    largest: Bytes,
     mmap: Mmap,
     index: Vec<SparseIndex>,
     bloomfilter: BloomFilter,
+    compression: Compression,
+}
+
+pub(crate) struct SSTMeta {
+    pub id: u64,
+    pub level: usize,
+    pub max_seqno: u64,
+    pub min_seqno: u64,
 }
 
 impl SSTable {
     pub(crate) fn new(
-        id: u64,
+        meta: SSTMeta,
         mmap: Mmap,
         index: Vec<SparseIndex>,
         bloomfilter: BloomFilter,
-    ) -> Self {
-        Self {
-            id,
+        compression: Compression,
+    ) -> crate::Result<Self> {
+        let mut table = Self {
+            id: meta.id,
+            level: meta.level,
+            max_seqno: meta.max_seqno,
+            min_seqno: meta.min_seqno,
+            smallest: Bytes::new(),
+            largest: Bytes::new(),
             mmap,
             index,
             bloomfilter,
+            compression,
+        };
+
+        if let Some(first) = table.index.first() {
+            table.smallest = first.first_key.clone();
         }
+        if let Some(last) = table.index.len().checked_sub(1) {
+            let block = table.block(last)?;
+            let mut cursor = table.cursor(&block)?;
+            cursor
+                .seek_restart(cursor.restart_count() - 1)
+                .ok_or(table.corrupted())?;
+
+            while !cursor.is_done() {
+                table.next_entry(&mut cursor)?;
+            }
+            table.largest = Bytes::from(cursor.key);
+        }
+        Ok(table)
     }
 
     pub(crate) fn contains(&self, key: &[u8]) -> bool {
         self.bloomfilter.contains(key)
     }
 
-    fn search(
-        &self,
-        key: &[u8],
-        target_offset: usize,
-        block_len: usize,
-    ) -> crate::Result<Option<Bytes>> {
-        let mut idx = target_offset;
-        let mmap = &self.mmap;
-        let end_bound = target_offset + block_len;
-
-        while idx + LEN_SIZE + OFFSET_SIZE <= end_bound {
-            let key_len = from_le_to_u32(mmap, idx, 0, LEN_SIZE)? as usize;
-            let key_start = idx + LEN_SIZE;
-            let key_end = key_start + key_len;
-            let found_key = &mmap[key_start..key_end];
-
-            if found_key == key {
-                let val_offset = from_le_to_u64(
-                    mmap,
-                    idx,
-                    LEN_SIZE + key_len,
-                    LEN_SIZE + key_len + OFFSET_SIZE,
-                )? as usize;
-                let val_len = from_le_to_u32(mmap, val_offset, 0, LEN_SIZE)? as usize;
-                let val = &mmap[LEN_SIZE + val_offset..LEN_SIZE + val_offset + val_len];
-
-                return Ok(Some(Bytes::copy_from_slice(val)));
+    pub(crate) fn smallest(&self) -> &[u8] {
+        &self.smallest
+    }
+
+    pub(crate) fn largest(&self) -> &[u8] {
+        &self.largest
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// The whole table file as mapped.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest() <= largest && smallest <= self.largest()
+    }
+
+    pub(crate) fn overlaps_bounds(&self, lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> bool {
+        let above_lower = match lower {
+            Bound::Included(k) => self.largest() >= &k[..],
+            Bound::Excluded(k) => self.largest() > &k[..],
+            Bound::Unbounded => true,
+        };
+        let below_upper = match upper {
+            Bound::Included(k) => self.smallest() <= &k[..],
+            Bound::Excluded(k) => self.smallest() < &k[..],
+            Bound::Unbounded => true,
+        };
+        above_lower && below_upper
+    }
+
+    pub(crate) fn iter(self: &Arc<Self>) -> SSTIter {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub(crate) fn range(self: &Arc<Self>, lower: Bound<Bytes>, upper: Bound<Bytes>) -> SSTIter {
+        let front_block = match &lower {
+            Bound::Included(k) | Bound::Excluded(k) => self
+                .index
+                .partition_point(|b| b.first_key <= k)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let back_block = match &upper {
+            Bound::Included(k) => self.index.partition_point(|b| b.first_key <= k),
+            Bound::Excluded(k) => self.index.partition_point(|b| b.first_key < k),
+            Bound::Unbounded => self.index.len(),
+        };
+
+        SSTIter {
+            table: self.clone(),
+            lower,
+            upper,
+            front_block,
+            back_block,
+            front_buf: VecDeque::new(),
+            back_buf: VecDeque::new(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> crate::Result<Vec<Entry>> {
+        let data = self.block(block)?;
+        let mut cursor = self.cursor(&data)?;
+        let mut entries = Vec::new();
+
+        while !cursor.is_done() {
+            let entry = self.next_entry(&mut cursor)?;
+            let val = Value::decode(entry.t, entry.val)?;
+            entries.push((Bytes::copy_from_slice(&cursor.key), entry.seqno, val));
+        }
+        Ok(entries)
+    }
+
+    /// Data block `block`, once its CRC32C trailer is verified
+    /// and its contents decompressed.
+    fn block(&self, block: usize) -> crate::Result<Cow<'_, [u8]>> {
+        let target = &self.index[block];
+        let (id, data) = checked(&self.mmap, target.offset, target.len)
+            .and_then(|b| b.split_last())
+            .ok_or(self.corrupted())?;
+        self.compression
+            .decompress(*id, data)?
+            .ok_or(self.corrupted())
+    }
+
+    fn cursor<'a>(&self, block: &'a [u8]) -> crate::Result<BlockCursor<'a>> {
+        BlockCursor::new(block).ok_or(self.corrupted())
+    }
+
+    fn next_entry<'a>(&self, cursor: &mut BlockCursor<'a>) -> crate::Result<BlockEntry<'a>> {
+        cursor.next_entry().ok_or(self.corrupted())
+    }
+
+    fn corrupted(&self) -> Error {
+        Error::SstCorrupted { sstno: self.id }
+    }
+
+    /// Versions of a key are stored newest first,
+    /// so the first one at or below `seqno` wins.
+    /// Returns a `Value::Tombstone` if that version is a delete
+    /// and `None` if the block holds no visible version of `key`.
+    fn search(&self, key: &[u8], seqno: u64, block: usize) -> crate::Result<Option<(u64, Value)>> {
+        let data = self.block(block)?;
+        let mut cursor = self.cursor(&data)?;
+
+        // Start from the last restart point below `key`. One holding `key`
+        // itself is too late, since its newer versions may come before it.
+        let (mut lo, mut hi) = (0, cursor.restart_count());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if cursor.restart_key(mid).ok_or(self.corrupted())? < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
             }
+        }
+        cursor
+            .seek_restart(lo.saturating_sub(1))
+            .ok_or(self.corrupted())?;
 
-            if found_key > key {
-                break;
+        while !cursor.is_done() {
+            let entry = self.next_entry(&mut cursor)?;
+
+            if cursor.key == key && entry.seqno <= seqno {
+                return Ok(Some((entry.seqno, Value::decode(entry.t, entry.val)?)));
             }
 
-            idx += LEN_SIZE + key_len + OFFSET_SIZE;
+            if &cursor.key[..] > key {
+                break;
+            }
         }
 
         Ok(None)
     }
 }
+
+/// Double-ended iterator over the entries of an `SSTable` within bounds.
+/// Blocks are decoded whole, one at a time, from either end.
+pub(crate) struct SSTIter {
+    table: Arc<SSTable>,
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    front_block: usize,
+    back_block: usize,
+    front_buf: VecDeque<Entry>,
+    back_buf: VecDeque<Entry>,
+}
+
+impl SSTIter {
+    fn load(&self, block: usize) -> crate::Result<VecDeque<Entry>> {
+        Ok(self
+            .table
+            .read_block(block)?
+            .into_iter()
+            .filter(|(key, _, _)| in_bounds(key, &self.lower, &self.upper))
+            .collect())
+    }
+}
+
+impl Iterator for SSTIter {
+    type Item = crate::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.front_buf.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.front_block >= self.back_block {
+                return self.back_buf.pop_front().map(Ok);
+            }
+
+            match self.load(self.front_block) {
+                Ok(entries) => {
+                    self.front_buf = entries;
+                    self.front_block += 1;
+                }
+                Err(e) => {
+                    self.front_block = self.back_block;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for SSTIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back_buf.pop_back() {
+                return Some(Ok(entry));
+            }
+            if self.front_block >= self.back_block {
+                return self.front_buf.pop_back().map(Ok);
+            }
+
+            match self.load(self.back_block - 1) {
+                Ok(entries) => {
+                    self.back_buf = entries;
+                    self.back_block -= 1;
+                }
+                Err(e) => {
+                    self.back_block = self.front_block;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{constants::DEFAULT_TREE, sst_writer::SSTBuilder};
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn bit_rot_in_a_data_block_is_detected() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let mut builder = SSTBuilder::new(dir.path(), DEFAULT_TREE, 1, 0, Compression::None)?;
+        for i in 0..2000u32 {
+            let key = Bytes::from(format!("key-{:05}", i));
+            builder.add(&key, 1, &Value::Data(Bytes::from(format!("val-{}", i))))?;
+        }
+        let (table, _) = builder.finish()?;
+        assert!(table.index.len() > 1);
+        assert_eq!(table.largest(), b"key-01999");
+        assert_eq!(table.get(b"key-00007", 1)?, Some(Bytes::from("val-7")));
+
+        // Flip one byte in the first block, past its first entry.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("sst-000001.log"))?;
+        file.seek(SeekFrom::Start((ENTRY_MIN_SIZE + 9 + 20) as u64))?;
+        file.write_all(b"X")?;
+        file.sync_all()?;
+
+        assert!(matches!(
+            table.get(b"key-00007", 1),
+            Err(Error::SstCorrupted { sstno: 1 })
+        ));
+        assert_eq!(table.get(b"key-01999", 1)?, Some(Bytes::from("val-1999")));
+        Ok(())
+    }
+
+    #[test]
+    fn search_finds_versions_across_restart_points() -> crate::Result<()> {
+        let dir = tempdir()?;
+        let mut builder = SSTBuilder::new(dir.path(), DEFAULT_TREE, 1, 0, Compression::None)?;
+        let path = |i: u32| Bytes::from(format!("/tenants/acme/projects/kepler/files/{:04}", i));
+        let mut full_keys = 0;
+
+        for i in 0..40u32 {
+            // Key 20 has more versions than a restart interval holds.
+            let versions = if i == 20 { 40 } else { 1 };
+            for seqno in (1..=versions).rev() {
+                builder.add(
+                    &path(i),
+                    seqno,
+                    &Value::Data(Bytes::from(format!("{i}@{seqno}"))),
+                )?;
+                full_keys += path(i).len();
+            }
+        }
+        let (table, _) = builder.finish()?;
+        let table = Arc::new(table);
+        assert_eq!(table.index.len(), 1);
+        // The whole table is smaller than its keys written out in full.
+        assert!(table.size() < full_keys);
+
+        for seqno in [40, 33, 17, 1] {
+            let want = Bytes::from(format!("20@{seqno}"));
+            assert_eq!(table.get(&path(20), seqno)?, Some(want));
+        }
+        for i in [0, 15, 16, 19, 21, 39] {
+            assert_eq!(table.get(&path(i), 1)?, Some(Bytes::from(format!("{i}@1"))));
+        }
+        assert_eq!(
+            table.get(b"/tenants/acme/projects/kepler/files/0020a", 1)?,
+            None
+        );
+        assert_eq!(table.get(b"/tenants/acme", 1)?, None);
+        assert_eq!(table.largest(), &path(39)[..]);
+        assert_eq!(table.iter().count(), 79);
+        Ok(())
+    }
+}
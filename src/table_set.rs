@@ -1,39 +1,71 @@
 use std::{
     mem::{self},
+    ops::Bound,
     path::Path,
-    sync::{Arc, RwLock, mpsc::Sender},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Sender, sync_channel},
+    },
 };
 
 use bytes::Bytes;
 
 use crate::{
     Error,
+    compaction::Compactor,
     constants::ACTIVE_CAP_MAX,
     imm_tables::ImmTables,
+    iter::{Iter, Source, TableMapIter},
     manifest::Manifest,
     mem_table::MemTable,
+    merge::{MergeOperator, resolve},
+    snapshot::SnapshotList,
     sst_manager::SSTManager,
     sst_writer::SSTWriter,
+    sstable::SSTable,
     traits::{Getable, Putable},
-    types::WorkerSignal,
+    types::{Value, WorkerSignal},
 };
 
 impl Getable for TableSet {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
-        let get_active = self.active.read().map_err(|_| Error::Poisoned)?.get(key);
+    /// Resolves merge operands, so the returned value is never
+    /// a `Value::Merge`. The seqno is the one of the newest version.
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        let mut next = Some(seqno);
+        let versions = std::iter::from_fn(|| {
+            let found = self.newest(key, next?);
+            next = match &found {
+                Ok(Some((s, _))) => s.checked_sub(1),
+                _ => None,
+            };
+            found.transpose()
+        });
+
+        resolve(self.merge_operator.as_deref(), key, versions)
+    }
+}
+
+impl TableSet {
+    // NOTE:
+    // A tombstone is returned as is, so a delete in a newer layer
+    // shadows every older value of the key below it.
+    fn newest(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>> {
+        let get_active = self
+            .active
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .lookup(key, seqno);
 
         if let Some(v) = get_active? {
             return Ok(Some(v));
         }
 
-        if let Some(v) = self.imm_tables.get(key)? {
+        if let Some(v) = self.imm_tables.lookup(key, seqno)? {
             return Ok(Some(v));
         }
 
-        if let Some(v) = self.sst_manager.get(key)? {
-            return Ok(Some(v));
-        }
-        Ok(None)
+        self.sst_manager.lookup(key, seqno)
     }
 }
 
@@ -41,22 +73,19 @@ impl Putable for TableSet {
     fn put(&self, seqno: u64, key: &[u8], val: Option<&[u8]>) -> crate::Result<()> {
         let mut active_ptr = self.active.write().map_err(|_| Error::Concurrency)?;
         active_ptr.put(seqno, key, val)?;
-
-        if active_ptr.bytes_written() >= ACTIVE_CAP_MAX {
-            let old = mem::replace(&mut *active_ptr, MemTable::new());
-            let table_map = Arc::new(old.take_tree()?);
-            self.imm_tables.push_back(table_map.clone())?;
-            self.sst_writer.send(WorkerSignal::Flush(table_map))?;
-        }
-        Ok(())
+        self.rotate_if_full(&mut active_ptr)
     }
 }
 
 pub struct TableSet {
+    pub(crate) id: u32,
     sst_writer: SSTWriter,
     active: RwLock<MemTable>,
+    /// Set while the active MemTable holds writes the WAL does not.
+    unlogged: AtomicBool,
     imm_tables: Arc<ImmTables>,
     sst_manager: Arc<SSTManager>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
 }
 
 impl TableSet {
@@ -65,24 +94,149 @@ impl TableSet {
         sst_manager: SSTManager,
         mem: MemTable,
         manifest: Arc<Manifest>,
+        snapshots: Arc<SnapshotList>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
         err_tx: Sender<WorkerSignal>,
     ) -> crate::Result<Self> {
         let active = RwLock::new(mem);
         let imm_tables = Arc::new(ImmTables::new());
         let sst_manager = Arc::new(sst_manager);
+        let compactor = Compactor::new(
+            path,
+            manifest.clone(),
+            sst_manager.clone(),
+            snapshots.clone(),
+            merge_operator.clone(),
+            err_tx.clone(),
+        )?;
         let sst_writer = SSTWriter::new(
             path,
             manifest,
             imm_tables.clone(),
             sst_manager.clone(),
+            compactor,
+            snapshots,
+            merge_operator.clone(),
             err_tx,
         )?;
 
         Ok(Self {
+            id: sst_manager.tree,
             sst_writer,
             active,
+            unlogged: AtomicBool::new(false),
             imm_tables,
             sst_manager,
+            merge_operator,
         })
     }
+
+    /// Flushes every ImmTable and joins the tree's workers.
+    /// The active MemTable stays in the WAL, unless frozen first
+    /// by `freeze_unlogged`.
+    pub(crate) fn shutdown(&self) -> crate::Result<()> {
+        self.sst_writer.shutdown()
+    }
+
+    /// Every SST of the tree, L0 first.
+    pub(crate) fn live_ssts(&self) -> crate::Result<Vec<Arc<SSTable>>> {
+        Ok(self.sst_manager.levels()?.into_iter().flatten().collect())
+    }
+
+    pub(crate) fn has_merge_operator(&self) -> bool {
+        self.merge_operator.is_some()
+    }
+
+    /// Applies the part of a batch aimed at this tree under one write lock
+    /// on the active MemTable, so readers observe all of it or none of it.
+    pub(crate) fn put_batch<'a>(
+        &self,
+        ops: impl Iterator<Item = (u64, &'a Bytes, &'a Value)>,
+    ) -> crate::Result<()> {
+        let mut active_ptr = self.active.write().map_err(|_| Error::Concurrency)?;
+
+        for (seqno, key, val) in ops {
+            active_ptr.insert(seqno, key, val.clone())?;
+        }
+        self.rotate_if_full(&mut active_ptr)
+    }
+
+    fn rotate_if_full(&self, active_ptr: &mut MemTable) -> crate::Result<()> {
+        if active_ptr.bytes_written() >= ACTIVE_CAP_MAX {
+            self.rotate(active_ptr)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self, active_ptr: &mut MemTable) -> crate::Result<()> {
+        self.unlogged.store(false, Ordering::Relaxed);
+        let old = mem::take(active_ptr);
+        let table_map = Arc::new(old.take_tree()?);
+        self.imm_tables.push_back(table_map.clone())?;
+        self.sst_writer.send(WorkerSignal::Flush(table_map))
+    }
+
+    /// Freezes the active MemTable unless it is empty.
+    /// The caller holds the journal lock, like every other writer.
+    pub(crate) fn freeze(&self) -> crate::Result<()> {
+        let mut active_ptr = self.active.write().map_err(|_| Error::Concurrency)?;
+        if active_ptr.bytes_written() > 0 {
+            self.rotate(&mut active_ptr)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the active MemTable as holding a write made without the WAL.
+    /// The caller holds the journal lock.
+    pub(crate) fn note_unlogged(&self) {
+        self.unlogged.store(true, Ordering::Relaxed);
+    }
+
+    /// Freezes the active MemTable if it holds writes the WAL cannot replay.
+    pub(crate) fn freeze_unlogged(&self) -> crate::Result<()> {
+        if self.unlogged.load(Ordering::Relaxed) {
+            self.freeze()?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until every frozen MemTable is an SST recorded in the manifest.
+    pub(crate) fn wait_flushed(&self) -> crate::Result<()> {
+        let (ack_tx, ack_rx) = sync_channel::<()>(1);
+        self.sst_writer.send(WorkerSignal::Sync(ack_tx))?;
+        ack_rx.recv().map_err(|_| Error::Poisoned)
+    }
+
+    // NOTE:
+    // Sources are captured newest first: active, then ImmTables, then SSTs.
+    // A table moving down the pipeline in between is seen twice, never missed.
+    pub(crate) fn range(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        seqno: u64,
+    ) -> crate::Result<Iter> {
+        let mut sources: Vec<Source> = Vec::new();
+
+        let active = self
+            .active
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .range(&lower, &upper)?;
+        sources.push(Box::new(active.into_iter().map(Ok)));
+
+        for table in self.imm_tables.tables()?.into_iter().rev() {
+            sources.push(Box::new(TableMapIter::new(
+                table,
+                lower.clone(),
+                upper.clone(),
+            )));
+        }
+
+        for sst_iter in self.sst_manager.range(&lower, &upper)? {
+            sources.push(Box::new(sst_iter));
+        }
+
+        Ok(Iter::new(sources, seqno, self.merge_operator.clone()))
+    }
 }
@@ -1,7 +1,27 @@
 use bytes::Bytes;
 
+use crate::{Error, types::Value, utils::now_millis};
+
 pub trait Getable {
-    fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>>;
+    /// Returns the newest version of `key` with a seqno up to `seqno`,
+    /// along with the seqno it was written at.
+    ///
+    /// A `Value::Tombstone` means the key was deleted at this layer,
+    /// so older layers must not be consulted.
+    fn lookup(&self, key: &[u8], seqno: u64) -> crate::Result<Option<(u64, Value)>>;
+
+    /// Returns the newest live value of `key` with a seqno up to `seqno`.
+    ///
+    /// A single layer cannot fold merge operands, so hitting one is an
+    /// error unless the implementor resolves them in `lookup`.
+    fn get(&self, key: &[u8], seqno: u64) -> crate::Result<Option<Bytes>> {
+        let found = self.lookup(key, seqno)?;
+        match found.map(|(seqno, val)| (seqno, val.expire(now_millis()))) {
+            Some((_, Value::Data(v) | Value::Expiring { val: v, .. })) => Ok(Some(v)),
+            Some((_, Value::Tombstone)) | None => Ok(None),
+            Some((_, Value::Merge(_))) => Err(Error::NoMergeOperator),
+        }
+    }
 }
 
 pub trait Putable {
@@ -0,0 +1,100 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    Error, batch::WriteBatch, db::KeplerInner, table_set::TableSet, traits::Getable, types::Value,
+};
+
+/// An optimistic read-modify-write transaction.
+///
+/// Writes are buffered until `commit`. Every key read through the
+/// transaction is remembered with the seqno it was observed at, and
+/// `commit` fails with `Error::Conflict` if any of them was overwritten
+/// in the meantime. Dropping the transaction discards its writes.
+pub struct Transaction {
+    db: Arc<KeplerInner>,
+    tables: Arc<TableSet>,
+    reads: BTreeMap<Bytes, Option<u64>>,
+    writes: BTreeMap<Bytes, Value>,
+}
+
+impl Transaction {
+    pub(crate) fn new(db: Arc<KeplerInner>, tables: Arc<TableSet>) -> Self {
+        Self {
+            db,
+            tables,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the latest value of `key`, seeing the transaction's own writes.
+    pub fn get(&mut self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        if let Some(val) = self.writes.get(key) {
+            return Ok(match val {
+                Value::Data(v) => Some(v.clone()),
+                _ => None,
+            });
+        }
+
+        let found = self.db.lookup(&self.tables, key)?;
+
+        // NOTE:
+        // Only the first observation counts. A later read seeing a newer
+        // seqno means the key changed under us, and commit will fail.
+        self.reads
+            .entry(Bytes::copy_from_slice(key))
+            .or_insert(found.as_ref().map(|(seqno, _)| *seqno));
+
+        match found {
+            Some((_, Value::Data(v))) => Ok(Some(v)),
+            Some(_) | None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
+        self.writes.insert(
+            Bytes::copy_from_slice(key),
+            Value::Data(Bytes::copy_from_slice(val)),
+        );
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.writes
+            .insert(Bytes::copy_from_slice(key), Value::Tombstone);
+    }
+
+    /// Validates every read and applies the buffered writes atomically.
+    ///
+    /// NOTE:
+    /// Validation runs under the journal lock, so no write can land
+    /// between the check and the batch being applied.
+    pub fn commit(self) -> crate::Result<()> {
+        self.db.check_thread_error()?;
+        let mut journal = self.db.lock_journal()?;
+
+        for (key, observed) in self.reads.iter() {
+            let current = self.tables.lookup(key, u64::MAX)?.map(|(seqno, _)| seqno);
+
+            if current != *observed {
+                return Err(Error::Conflict);
+            }
+        }
+
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let batch = WriteBatch {
+            ops: self
+                .writes
+                .into_iter()
+                .map(|(key, val)| (None, key, val))
+                .collect(),
+            ..WriteBatch::default()
+        };
+        self.db
+            .write_locked(&mut journal, &self.tables, &batch, &self.db.write_options)
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+
+use crate::{batch::WriteBatch, db::Kepler, lock_manager::LockManager, types::Value};
+
+/// A `Kepler` wrapper for pessimistic transactions.
+///
+/// Transactions lock every key they write or read through
+/// `get_for_update` until they commit or are dropped. A lock that is
+/// not granted within the lock timeout fails with `Error::LockTimeout`,
+/// and one that would close a wait cycle fails with `Error::Deadlock`.
+pub struct TransactionDb {
+    db: Kepler,
+    locks: Arc<LockManager>,
+}
+
+impl TransactionDb {
+    pub fn new(db: Kepler, lock_timeout: Duration) -> Self {
+        Self {
+            db,
+            locks: Arc::new(LockManager::new(lock_timeout)),
+        }
+    }
+
+    pub fn transaction(&self) -> PessimisticTransaction {
+        PessimisticTransaction {
+            id: self.locks.next_id(),
+            db: self.db.clone(),
+            locks: self.locks.clone(),
+            locked: Vec::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        self.db.get(key)
+    }
+
+    /// Writes `key` as a single-key transaction,
+    /// waiting for any transaction that holds its lock.
+    pub fn insert(&self, key: &[u8], val: &[u8]) -> crate::Result<()> {
+        let mut tx = self.transaction();
+        tx.insert(key, val)?;
+        tx.commit()
+    }
+
+    pub fn remove(&self, key: &[u8]) -> crate::Result<()> {
+        let mut tx = self.transaction();
+        tx.remove(key)?;
+        tx.commit()
+    }
+}
+
+/// A transaction holding key locks until it commits or is dropped.
+/// Dropping it without `commit` discards its writes.
+pub struct PessimisticTransaction {
+    id: u64,
+    db: Kepler,
+    locks: Arc<LockManager>,
+    locked: Vec<Bytes>,
+    writes: BTreeMap<Bytes, Value>,
+}
+
+impl PessimisticTransaction {
+    /// Reads the latest value of `key` without locking it,
+    /// seeing the transaction's own writes.
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        match self.writes.get(key) {
+            Some(Value::Data(v)) => Ok(Some(v.clone())),
+            Some(_) => Ok(None),
+            None => self.db.get(key),
+        }
+    }
+
+    /// Locks `key`, then reads its latest value.
+    /// No other transaction can change it until this one ends.
+    pub fn get_for_update(&mut self, key: &[u8]) -> crate::Result<Option<Bytes>> {
+        self.lock(key)?;
+        self.get(key)
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> crate::Result<()> {
+        self.lock(key)?;
+        self.writes.insert(
+            Bytes::copy_from_slice(key),
+            Value::Data(Bytes::copy_from_slice(val)),
+        );
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> crate::Result<()> {
+        self.lock(key)?;
+        self.writes
+            .insert(Bytes::copy_from_slice(key), Value::Tombstone);
+        Ok(())
+    }
+
+    /// Applies the buffered writes as one atomic journal record,
+    /// then releases every lock.
+    pub fn commit(mut self) -> crate::Result<()> {
+        let batch = WriteBatch {
+            ops: std::mem::take(&mut self.writes)
+                .into_iter()
+                .map(|(key, val)| (None, key, val))
+                .collect(),
+            ..WriteBatch::default()
+        };
+        self.db.write(batch)
+    }
+
+    fn lock(&mut self, key: &[u8]) -> crate::Result<()> {
+        if self.locks.lock(self.id, key)? {
+            self.locked.push(Bytes::copy_from_slice(key));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        let _ = self.locks.unlock_all(self.id, &self.locked);
+    }
+}
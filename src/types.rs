@@ -1,20 +1,103 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    sync::{Arc, Mutex, mpsc::SyncSender},
+    thread::JoinHandle,
+};
 
 use bytes::Bytes;
 
-use crate::Error;
+use crate::{Error, constants::SEQNO_SIZE};
 
 #[derive(Clone)]
 pub enum Value {
     Tombstone,
     Data(Bytes),
+    /// An operand folded onto older versions by the `MergeOperator`.
+    Merge(Bytes),
+    /// Data that reads as deleted once `expires_at`, in unix millis, has passed.
+    Expiring {
+        val: Bytes,
+        expires_at: u64,
+    },
+}
+
+impl Value {
+    /// Type byte and payload shared by WAL batch entries and SST entries.
+    ///
+    /// Types
+    ///     - 0 data, 1 tombstone (no payload), 2 merge operand,
+    ///         3 expiring data with payload expires_at(8) + val
+    pub(crate) fn encode(&self) -> (u8, Option<Cow<'_, [u8]>>) {
+        match self {
+            Value::Data(v) => (0, Some(Cow::Borrowed(v))),
+            Value::Tombstone => (1, None),
+            Value::Merge(v) => (2, Some(Cow::Borrowed(v))),
+            Value::Expiring { val, expires_at } => {
+                let mut payload = expires_at.to_le_bytes().to_vec();
+                payload.extend_from_slice(val);
+                (3, Some(Cow::Owned(payload)))
+            }
+        }
+    }
+
+    pub(crate) fn decode(t: u8, payload: &[u8]) -> crate::Result<Self> {
+        match t {
+            0 => Ok(Value::Data(Bytes::copy_from_slice(payload))),
+            1 => Ok(Value::Tombstone),
+            2 => Ok(Value::Merge(Bytes::copy_from_slice(payload))),
+            3 if payload.len() >= SEQNO_SIZE => Ok(Value::Expiring {
+                val: Bytes::copy_from_slice(&payload[SEQNO_SIZE..]),
+                expires_at: u64::from_le_bytes(payload[..SEQNO_SIZE].try_into().unwrap()),
+            }),
+            _ => Err(Error::Corrupted),
+        }
+    }
+
+    /// Turns an `Expiring` value past its deadline into a `Tombstone`.
+    pub fn expire(self, now: u64) -> Self {
+        match self {
+            Value::Expiring { expires_at, .. } if expires_at <= now => Value::Tombstone,
+            val => val,
+        }
+    }
 }
 
 pub enum WorkerSignal {
     Flush(Arc<TableMap>),
-    #[allow(dead_code)]
+    Compact,
+    /// Acknowledged once every earlier flush is durable in the manifest.
+    Sync(SyncSender<()>),
     Shutdown,
     Panic(Error),
 }
 
-pub type TableMap = BTreeMap<Bytes, (u64, Value)>;
+/// Asks a background worker to stop once its queue drains, then joins it.
+///
+/// NOTE:
+/// A worker that failed has already sent its error as `Panic` and
+/// dropped its receiver, so the send failing is no error of its own.
+pub(crate) fn stop_worker(
+    sender: &SyncSender<WorkerSignal>,
+    handle: JoinHandle<()>,
+) -> crate::Result<()> {
+    let _ = sender.send(WorkerSignal::Shutdown);
+    handle.join().map_err(|_| Error::Poisoned)
+}
+
+/// Every version of a key, oldest first.
+pub type Versions = Vec<(u64, Value)>;
+
+pub type TableMap = BTreeMap<Bytes, Versions>;
+
+/// First seqno of each tree that is not yet in a durable SST,
+/// kept current by the manifest writer.
+pub(crate) type FlushedSeqnos = Arc<Mutex<BTreeMap<u32, u64>>>;
+
+/// Returns the newest version visible at `seqno`.
+pub fn newest_visible(versions: &Versions, seqno: u64) -> Option<&(u64, Value)> {
+    versions.iter().rev().find(|(s, _)| *s <= seqno)
+}
+
+/// A single version of a key: (key, seqno, value).
+pub type Entry = (Bytes, u64, Value);
@@ -1,5 +1,11 @@
-use memmap2::Mmap;
-use std::{fs, path::Path};
+use bytes::Bytes;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub(crate) fn ensure_dir(path: &Path) -> std::io::Result<()> {
     if !path.exists() {
@@ -8,24 +14,140 @@ pub(crate) fn ensure_dir(path: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
-pub(crate) fn from_le_to_u64(
-    data: &Mmap,
-    idx: usize,
-    start_idx: usize,
-    end_idx: usize,
-) -> crate::Result<u64> {
-    let mut arr = [0u8; 8];
-    arr.copy_from_slice(&data[idx + start_idx..idx + end_idx]);
-    Ok(u64::from_le_bytes(arr))
-}
-
-pub(crate) fn from_le_to_u32(
-    data: &Mmap,
-    idx: usize,
-    start_idx: usize,
-    end_idx: usize,
-) -> crate::Result<u32> {
-    let mut arr = [0u8; 4];
-    arr.copy_from_slice(&data[idx + start_idx..idx + end_idx]);
-    Ok(u32::from_le_bytes(arr))
+/// Fsyncs a directory so that entries created or renamed in it survive a crash.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Name a file is written under until `rename_synced` publishes it.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    path.with_extension("tmp")
+}
+
+/// Renames the fsynced file `tmp` to `path`, then fsyncs the directory.
+/// A crash leaves either no file at `path` or the complete one.
+pub(crate) fn rename_synced(tmp: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(tmp, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Creates an empty file at `path` the same way and opens it for appending.
+pub(crate) fn create_synced(path: &Path) -> io::Result<File> {
+    let tmp = tmp_path(path);
+    File::create(&tmp)?.sync_all()?;
+    rename_synced(&tmp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Writes `data` to a new file at `path` the same way.
+pub(crate) fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename_synced(&tmp, path)
+}
+
+/// Deletes the temp files a crash left behind in `dir`.
+pub(crate) fn remove_tmp_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") && path.is_file() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hard-links `src` to `dst`, copying instead across file systems.
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Current wall clock time in unix millis, used for TTL expiry.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Little-endian `u32` at `idx`, if `data` is long enough.
+pub(crate) fn read_u32(data: &[u8], idx: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(idx..idx + 4)?.try_into().ok()?))
+}
+
+/// Little-endian `u64` at `idx`, if `data` is long enough.
+pub(crate) fn read_u64(data: &[u8], idx: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(idx..idx + 8)?.try_into().ok()?))
+}
+
+pub(crate) fn in_bounds(key: &[u8], lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> bool {
+    let above_lower = match lower {
+        Bound::Included(k) => key >= &k[..],
+        Bound::Excluded(k) => key > &k[..],
+        Bound::Unbounded => true,
+    };
+    let below_upper = match upper {
+        Bound::Included(k) => key <= &k[..],
+        Bound::Excluded(k) => key < &k[..],
+        Bound::Unbounded => true,
+    };
+    above_lower && below_upper
+}
+
+/// `BTreeMap::range` panics on inverted bounds, so check them first.
+pub(crate) fn is_valid_range(lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(a), Bound::Included(b)) => a <= b,
+        (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => a < b,
+        _ => true,
+    }
+}
+
+pub(crate) type SliceRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+pub(crate) fn as_slice_bound(bound: &Bound<Bytes>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(k) => Bound::Included(k.as_ref()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+pub(crate) fn owned_bounds<K: AsRef<[u8]>, R: RangeBounds<K>>(
+    range: R,
+) -> (Bound<Bytes>, Bound<Bytes>) {
+    let lower = range
+        .start_bound()
+        .map(|k| Bytes::copy_from_slice(k.as_ref()));
+    let upper = range
+        .end_bound()
+        .map(|k| Bytes::copy_from_slice(k.as_ref()));
+    (lower, upper)
+}
+
+pub(crate) fn prefix_bounds(prefix: &[u8]) -> (Bound<Bytes>, Bound<Bytes>) {
+    let lower = Bound::Included(Bytes::copy_from_slice(prefix));
+    let upper = match prefix_upper_bound(prefix) {
+        Some(k) => Bound::Excluded(k),
+        None => Bound::Unbounded,
+    };
+    (lower, upper)
+}
+
+/// Smallest key greater than every key starting with `prefix`,
+/// or `None` when no such key exists (e.g. the prefix is all `0xff`).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Bytes> {
+    let mut upper = prefix.to_vec();
+
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Some(Bytes::from(upper));
+        }
+    }
+    None
 }
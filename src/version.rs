@@ -1,8 +1,11 @@
 use std::collections::BTreeMap;
 
 pub struct Version {
-    #[allow(dead_code)]
     pub sst_list: BTreeMap<u64, SSTInfo>,
+    /// Named trees and their ids. The default tree has no name.
+    pub trees: BTreeMap<String, u32>,
+    /// First seqno of each tree that is not yet in an SST.
+    pub replay_from: BTreeMap<u32, u64>,
     pub next_seqno: u64,
     pub next_sstno: u64,
 }
@@ -11,19 +14,28 @@ impl Version {
     pub fn new(sst_list: BTreeMap<u64, SSTInfo>, next_seqno: u64, next_sstno: u64) -> Self {
         Self {
             sst_list,
+            trees: BTreeMap::new(),
+            replay_from: BTreeMap::new(),
             next_seqno,
             next_sstno,
         }
     }
+
+    /// WAL records of `tree` below the returned seqno are already flushed.
+    pub fn replay_from(&self, tree: u32) -> u64 {
+        self.replay_from.get(&tree).copied().unwrap_or(0)
+    }
 }
 
 pub struct SSTInfo {
     #[allow(dead_code)]
     pub id: u64,
+    pub tree: u32,
+    pub level: usize,
 }
 
 impl SSTInfo {
-    pub fn new(id: u64) -> Self {
-        Self { id }
+    pub fn new(id: u64, tree: u32, level: usize) -> Self {
+        Self { id, tree, level }
     }
 }
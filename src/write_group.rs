@@ -0,0 +1,186 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex},
+};
+
+use crate::Error;
+
+/// Leader/follower group commit.
+///
+/// Writers queue their request and wait. The first one to find no leader
+/// takes every queued request, commits them together and hands each
+/// writer its own result, so a burst of writers shares one WAL flush
+/// and fsync instead of paying one each.
+pub(crate) struct WriteGroup<R> {
+    state: Mutex<GroupState<R>>,
+    cond: Condvar,
+}
+
+struct GroupState<R> {
+    queue: Vec<(u64, R)>,
+    results: BTreeMap<u64, crate::Result<()>>,
+    next_ticket: u64,
+    leading: bool,
+}
+
+impl<R> WriteGroup<R> {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(GroupState {
+                queue: Vec::new(),
+                results: BTreeMap::new(),
+                next_ticket: 0,
+                leading: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Queues `request` and blocks until it is committed.
+    /// `commit` runs on the leader with the whole group, in queue order,
+    /// and returns one result per request.
+    pub(crate) fn commit<F>(&self, request: R, commit: F) -> crate::Result<()>
+    where
+        F: FnOnce(&[R]) -> Vec<crate::Result<()>>,
+    {
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push((ticket, request));
+
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+            if !state.leading {
+                break;
+            }
+            state = self.cond.wait(state).map_err(|_| Error::Poisoned)?;
+        }
+
+        state.leading = true;
+        let group = std::mem::take(&mut state.queue);
+        drop(state);
+
+        let (tickets, requests): (Vec<u64>, Vec<R>) = group.into_iter().unzip();
+        {
+            let _lead = Lead {
+                group: self,
+                tickets: &tickets,
+            };
+            let results = commit(&requests);
+            let mut state = self.state.lock().map_err(|_| Error::Poisoned)?;
+            state.results.extend(tickets.iter().copied().zip(results));
+        }
+
+        self.state
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .results
+            .remove(&ticket)
+            .unwrap_or(Err(Error::Poisoned))
+    }
+}
+
+/// Hands the lead back when dropped, even if `commit` panics.
+/// Requests of the group left without a result fail with `Error::Poisoned`.
+struct Lead<'a, R> {
+    group: &'a WriteGroup<R>,
+    tickets: &'a [u64],
+}
+
+impl<R> Drop for Lead<'_, R> {
+    fn drop(&mut self) {
+        // Waiters must be woken even if the state lock is poisoned.
+        let mut state = self.group.state.lock().unwrap_or_else(|e| e.into_inner());
+        for ticket in self.tickets.iter() {
+            state.results.entry(*ticket).or_insert(Err(Error::Poisoned));
+        }
+        state.leading = false;
+        self.group.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn concurrent_writers_share_a_leader() {
+        let group = Arc::new(WriteGroup::new());
+        let barrier = Arc::new(Barrier::new(8));
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|i| {
+                let (group, barrier, sizes) = (group.clone(), barrier.clone(), sizes.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    group.commit(i, |requests| {
+                        // Hold the lead long enough for the others to queue up.
+                        thread::sleep(Duration::from_millis(20));
+                        sizes.lock().unwrap().push(requests.len());
+                        requests
+                            .iter()
+                            .map(|r| {
+                                if r % 2 == 0 {
+                                    Ok(())
+                                } else {
+                                    Err(Error::Conflict)
+                                }
+                            })
+                            .collect()
+                    })
+                })
+            })
+            .collect();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.is_ok(), i % 2 == 0);
+        }
+
+        let sizes = sizes.lock().unwrap();
+        assert_eq!(sizes.iter().sum::<usize>(), 8);
+        assert!(sizes.len() < 8);
+    }
+
+    #[test]
+    fn panicking_leader_fails_its_group_and_hands_over() {
+        let group = Arc::new(WriteGroup::new());
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+
+        let leader = {
+            let group = group.clone();
+            thread::spawn(move || {
+                group.commit(0u64, |_| {
+                    entered_tx.send(()).unwrap();
+                    // Let a follower queue up behind the lead.
+                    thread::sleep(Duration::from_millis(50));
+                    panic!("commit failed");
+                })
+            })
+        };
+        entered_rx.recv().unwrap();
+        let follower = {
+            let group = group.clone();
+            thread::spawn(move || {
+                group.commit(1, |requests| requests.iter().map(|_| Ok(())).collect())
+            })
+        };
+
+        assert!(leader.join().is_err());
+        // The follower queued after the group was taken and leads its own.
+        assert!(follower.join().unwrap().is_ok());
+        assert!(
+            group
+                .commit(2, |requests| requests.iter().map(|_| Ok(())).collect())
+                .is_ok()
+        );
+    }
+}
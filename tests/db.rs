@@ -1,5 +1,9 @@
 use bytes::Bytes;
-use kepler::Kepler;
+use kepler::{
+    BackupEngine, Compression, Compressor, Error, Kepler, MergeOperator, Options, TransactionDb,
+    WriteBatch, WriteOptions,
+};
+use std::{sync::Arc, thread, time::Duration};
 use tempfile::tempdir;
 
 #[test]
@@ -83,3 +87,842 @@ fn try_get_after_flush() -> kepler::Result<()> {
     Ok(())
 }
 
+#[test]
+fn range_scan_in_key_order() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    for key in ["d", "a", "c", "e", "b"] {
+        db.insert(key.as_bytes(), key.as_bytes())?;
+    }
+    db.insert(b"c", b"updated")?;
+    db.remove(b"d")?;
+
+    let all: Vec<(Bytes, Bytes)> = db.iter()?.collect::<kepler::Result<_>>()?;
+    let keys: Vec<&[u8]> = all.iter().map(|(k, _)| k.as_ref()).collect();
+    assert_eq!(keys, vec![&b"a"[..], b"b", b"c", b"e"]);
+    assert_eq!(all[2].1, Bytes::from("updated"));
+
+    let keys: Vec<Bytes> = db
+        .range(&b"b"[..]..&b"e"[..])?
+        .rev()
+        .map(|r| r.map(|(k, _)| k))
+        .collect::<kepler::Result<_>>()?;
+    assert_eq!(keys, vec![Bytes::from("c"), Bytes::from("b")]);
+
+    Ok(())
+}
+
+#[test]
+fn scan_prefix_lists_only_matching_keys() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    db.insert(b"user/1", b"alice")?;
+    db.insert(b"user/2", b"bob")?;
+    db.insert(b"users", b"-")?;
+    db.insert(b"user0", b"-")?;
+    db.insert(b"team/1", b"-")?;
+
+    let keys: Vec<Bytes> = db
+        .scan_prefix(b"user/")?
+        .map(|r| r.map(|(k, _)| k))
+        .collect::<kepler::Result<_>>()?;
+    assert_eq!(keys, vec![Bytes::from("user/1"), Bytes::from("user/2")]);
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_applies_all_ops() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        db.insert(b"gone", b"soon")?;
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"apple", b"red");
+        batch.put(b"banana", b"yellow");
+        batch.remove(b"gone");
+        db.write(batch)?;
+
+        assert_eq!(db.get(b"apple")?, Some(Bytes::from("red")));
+        assert_eq!(db.get(b"banana")?, Some(Bytes::from("yellow")));
+        assert_eq!(db.get(b"gone")?, None);
+    }
+
+    let db = Kepler::new(dir.path())?;
+    assert_eq!(db.get(b"apple")?, Some(Bytes::from("red")));
+    assert_eq!(db.get(b"gone")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_ignores_later_writes() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    db.insert(b"a", b"1")?;
+    db.insert(b"b", b"1")?;
+    let snapshot = db.snapshot()?;
+
+    db.insert(b"a", b"2")?;
+    db.remove(b"b")?;
+    db.insert(b"c", b"2")?;
+
+    assert_eq!(snapshot.get(b"a")?, Some(Bytes::from("1")));
+    assert_eq!(snapshot.get(b"b")?, Some(Bytes::from("1")));
+    assert_eq!(snapshot.get(b"c")?, None);
+    assert_eq!(db.get(b"a")?, Some(Bytes::from("2")));
+    assert_eq!(db.get(b"b")?, None);
+
+    let keys: Vec<Bytes> = snapshot
+        .iter()?
+        .map(|r| r.map(|(k, _)| k))
+        .collect::<kepler::Result<_>>()?;
+    assert_eq!(keys, vec![Bytes::from("a"), Bytes::from("b")]);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_commits_read_modify_write() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+    db.insert(b"apple", b"3")?;
+    db.insert(b"pear", b"1")?;
+
+    let mut tx = db.transaction();
+    assert_eq!(tx.get(b"apple")?, Some(Bytes::from("3")));
+    tx.insert(b"apple", b"2");
+    tx.insert(b"pear", b"2");
+    tx.remove(b"plum");
+    assert_eq!(tx.get(b"apple")?, Some(Bytes::from("2")));
+    assert_eq!(db.get(b"apple")?, Some(Bytes::from("3")));
+    tx.commit()?;
+
+    assert_eq!(db.get(b"apple")?, Some(Bytes::from("2")));
+    assert_eq!(db.get(b"pear")?, Some(Bytes::from("2")));
+    Ok(())
+}
+
+#[test]
+fn transaction_conflicts_on_overwritten_read() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+    db.insert(b"apple", b"3")?;
+
+    let mut tx = db.transaction();
+    assert_eq!(tx.get(b"apple")?, Some(Bytes::from("3")));
+    assert_eq!(tx.get(b"pear")?, None);
+    tx.insert(b"apple", b"2");
+
+    db.insert(b"pear", b"1")?;
+    assert!(matches!(tx.commit(), Err(Error::Conflict)));
+    assert_eq!(db.get(b"apple")?, Some(Bytes::from("3")));
+    Ok(())
+}
+
+#[test]
+fn pessimistic_transactions_serialize_counter_updates() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Arc::new(TransactionDb::new(
+        Kepler::new(dir.path())?,
+        Duration::from_secs(5),
+    ));
+    db.insert(b"counter", b"0")?;
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            thread::spawn(move || -> kepler::Result<()> {
+                for _ in 0..25 {
+                    let mut tx = db.transaction();
+                    let cur = tx.get_for_update(b"counter")?.unwrap();
+                    let next = std::str::from_utf8(&cur).unwrap().parse::<u64>().unwrap() + 1;
+                    tx.insert(b"counter", next.to_string().as_bytes())?;
+                    tx.commit()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    assert_eq!(db.get(b"counter")?, Some(Bytes::from("100")));
+    Ok(())
+}
+
+#[test]
+fn pessimistic_transaction_lock_times_out() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = TransactionDb::new(Kepler::new(dir.path())?, Duration::from_millis(20));
+
+    let mut holder = db.transaction();
+    holder.get_for_update(b"k")?;
+
+    let mut other = db.transaction();
+    assert!(matches!(
+        other.get_for_update(b"k"),
+        Err(Error::LockTimeout)
+    ));
+
+    drop(holder);
+    other.insert(b"k", b"v")?;
+    other.commit()?;
+    assert_eq!(db.get(b"k")?, Some(Bytes::from("v")));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_checks_current_value() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    assert!(db.compare_and_swap(b"lease", None, Some(b"a"))?.is_ok());
+    let err = db
+        .compare_and_swap(b"lease", None, Some(b"b"))?
+        .unwrap_err();
+    assert_eq!(err.current, Some(Bytes::from("a")));
+
+    assert!(db.compare_and_swap(b"lease", Some(b"a"), None)?.is_ok());
+    assert_eq!(db.get(b"lease")?, None);
+    Ok(())
+}
+
+#[test]
+fn update_and_fetch_allocates_unique_ids() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            thread::spawn(move || -> kepler::Result<Vec<u64>> {
+                let mut ids = Vec::new();
+                for _ in 0..25 {
+                    let id = db.update_and_fetch(b"id", |old| {
+                        let old = old.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+                        Some((old + 1).to_le_bytes())
+                    })?;
+                    ids.push(u64::from_le_bytes(id.unwrap()[..].try_into().unwrap()));
+                }
+                Ok(ids)
+            })
+        })
+        .collect();
+
+    let mut ids = Vec::new();
+    for worker in workers {
+        ids.extend(worker.join().unwrap()?);
+    }
+    ids.sort();
+    assert_eq!(ids, (1..=100).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn update_and_fetch_closure_may_write_to_the_database() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    let new = db.update_and_fetch(b"a", |old| {
+        db.insert(b"b", b"2").unwrap();
+        Some(old.map_or(b"1".to_vec(), |v| [v, b"!"].concat()))
+    })?;
+    assert_eq!(new, Some(Bytes::from("1")));
+    assert_eq!(db.get(b"b")?, Some(Bytes::from("2")));
+    Ok(())
+}
+
+struct Counter;
+
+impl MergeOperator for Counter {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let old = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+        let delta = u64::from_le_bytes(operand.try_into().unwrap());
+        Some((old + delta).to_le_bytes().to_vec())
+    }
+}
+
+#[test]
+fn merge_folds_operands_on_read_and_replay() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let count = |v: Option<Bytes>| v.map(|v| u64::from_le_bytes(v[..].try_into().unwrap()));
+
+    {
+        let db = Kepler::with_merge_operator(dir.path(), Counter)?;
+        db.merge(b"hits", &1u64.to_le_bytes())?;
+        db.merge(b"hits", &2u64.to_le_bytes())?;
+        let snapshot = db.snapshot()?;
+        db.merge(b"hits", &3u64.to_le_bytes())?;
+
+        assert_eq!(count(db.get(b"hits")?), Some(6));
+        assert_eq!(count(snapshot.get(b"hits")?), Some(3));
+
+        db.insert(b"hits", &10u64.to_le_bytes())?;
+        db.merge(b"hits", &5u64.to_le_bytes())?;
+        let (_, val) = db.iter()?.next().unwrap()?;
+        assert_eq!(count(Some(val)), Some(15));
+    }
+
+    let db = Kepler::with_merge_operator(dir.path(), Counter)?;
+    assert_eq!(count(db.get(b"hits")?), Some(15));
+    Ok(())
+}
+
+#[test]
+fn merge_without_operator_is_rejected() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+
+    assert!(matches!(db.merge(b"k", b"v"), Err(Error::NoMergeOperator)));
+    assert_eq!(db.get(b"k")?, None);
+    Ok(())
+}
+
+#[test]
+fn ttl_entries_expire_on_read_and_replay() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        db.insert_with_ttl(b"session", b"short", Duration::from_millis(50))?;
+        db.insert_with_ttl(b"token", b"long", Duration::from_secs(3600))?;
+        assert_eq!(db.get(b"session")?, Some(Bytes::from("short")));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(db.get(b"session")?, None);
+        let keys: Vec<Bytes> = db.iter()?.map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![Bytes::from("token")]);
+    }
+
+    let db = Kepler::new(dir.path())?;
+    assert_eq!(db.get(b"session")?, None);
+    assert_eq!(db.get(b"token")?, Some(Bytes::from("long")));
+    Ok(())
+}
+
+#[test]
+fn trees_are_isolated_and_batches_span_them() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        let users = db.open_tree("users")?;
+        let index = db.open_tree("index")?;
+
+        db.insert(b"k", b"default")?;
+        users.insert(b"k", b"users")?;
+        assert_eq!(index.get(b"k")?, None);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"alice", b"1");
+        batch.put_in(&index, b"1", b"alice");
+        batch.remove_in(&db, b"k");
+        users.write(batch)?;
+
+        assert_eq!(users.get(b"alice")?, Some(Bytes::from("1")));
+        assert_eq!(index.get(b"1")?, Some(Bytes::from("alice")));
+        assert_eq!(db.get(b"k")?, None);
+        assert_eq!(
+            db.open_tree("users")?.get(b"k")?,
+            Some(Bytes::from("users"))
+        );
+    }
+
+    let db = Kepler::new(dir.path())?;
+    let users = db.open_tree("users")?;
+    let index = db.open_tree("index")?;
+    assert_eq!(db.get(b"k")?, None);
+    assert_eq!(users.get(b"k")?, Some(Bytes::from("users")));
+    assert_eq!(index.get(b"1")?, Some(Bytes::from("alice")));
+
+    let keys: Vec<Bytes> = users.iter()?.map(|e| e.unwrap().0).collect();
+    assert_eq!(keys, vec![Bytes::from("alice"), Bytes::from("k")]);
+    Ok(())
+}
+
+#[test]
+fn batch_rejects_trees_of_another_database() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path().join("one"))?;
+    let other = Kepler::new(dir.path().join("two"))?;
+    let other_tree = other.open_tree("users")?;
+    db.open_tree("users")?;
+
+    let mut batch = WriteBatch::new();
+    batch.put_in(&other_tree, b"k", b"v");
+    assert!(matches!(db.write(batch.clone()), Err(Error::UnknownTree)));
+    other.write(batch)?;
+    assert_eq!(other_tree.get(b"k")?, Some(Bytes::from("v")));
+
+    let mut batch = WriteBatch::new();
+    batch.put_in(&db, b"a", b"1");
+    batch.put_in(&other_tree, b"b", b"2");
+    assert!(matches!(other.write(batch), Err(Error::UnknownTree)));
+    assert_eq!(db.get(b"a")?, None);
+    Ok(())
+}
+
+#[test]
+fn checkpoint_opens_as_independent_copy() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let target = dir.path().join("checkpoint");
+    let db = Kepler::new(dir.path().join("db"))?;
+    let blobs = db.open_tree("blobs")?;
+
+    let big_val = vec![1u8; 32 * 1024 * 1024]; //ACTIVE_CAP_MAX = 32MB
+    blobs.insert(b"big", &big_val)?;
+    blobs.insert(b"small", b"tail")?;
+    db.insert(b"k", b"before")?;
+    thread::sleep(Duration::from_millis(200));
+
+    db.checkpoint(&target)?;
+    db.insert(b"k", b"after")?;
+    blobs.remove(b"small")?;
+    assert!(db.checkpoint(&target).is_err());
+
+    let copy = Kepler::new(&target)?;
+    let copy_blobs = copy.open_tree("blobs")?;
+    assert_eq!(copy.get(b"k")?, Some(Bytes::from("before")));
+    assert_eq!(copy_blobs.get(b"small")?, Some(Bytes::from("tail")));
+    assert_eq!(
+        copy_blobs.get(b"big")?,
+        Some(Bytes::copy_from_slice(&big_val))
+    );
+
+    copy.insert(b"k", b"copy")?;
+    assert_eq!(db.get(b"k")?, Some(Bytes::from("after")));
+    Ok(())
+}
+
+#[test]
+fn incremental_backups_share_ssts_and_restore() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path().join("db"))?;
+    let engine = BackupEngine::open(dir.path().join("backups"))?;
+
+    let big_val = vec![1u8; 32 * 1024 * 1024]; //ACTIVE_CAP_MAX = 32MB
+    db.insert(b"big", &big_val)?;
+    db.flush()?;
+    db.insert(b"k", b"first")?;
+    assert_eq!(engine.create_backup(&db)?, 1);
+
+    db.insert(b"k", b"second")?;
+    assert_eq!(engine.create_backup(&db)?, 2);
+    let shared = std::fs::read_dir(dir.path().join("backups/sst"))?.count();
+    assert_eq!(shared, 1);
+
+    engine.verify_backup(1)?;
+    engine.restore_latest(dir.path().join("restored"))?;
+    let restored = Kepler::new(dir.path().join("restored"))?;
+    assert_eq!(restored.get(b"k")?, Some(Bytes::from("second")));
+    assert_eq!(
+        restored.get(b"big")?,
+        Some(Bytes::copy_from_slice(&big_val))
+    );
+
+    engine.purge_old(1)?;
+    assert_eq!(engine.backups()?, vec![2]);
+    engine.verify_backup(2)?;
+
+    std::fs::write(
+        dir.path().join("backups/backup-000002/manifest-000001.log"),
+        b"garbage",
+    )?;
+    assert!(matches!(engine.verify_backup(2), Err(Error::Corrupted)));
+    assert!(matches!(
+        engine.restore_latest(dir.path().join("broken")),
+        Err(Error::Corrupted)
+    ));
+    Ok(())
+}
+
+#[test]
+fn backups_keep_same_named_ssts_with_different_contents_apart() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let engine = BackupEngine::open(dir.path().join("backups"))?;
+
+    // Both databases write an sst-000001.log of the same size, and even
+    // the same whole-file CRC32C, as only a checksummed block differs.
+    for (name, val) in [("one", b"1"), ("two", b"2")] {
+        let db = Kepler::new(dir.path().join(name))?;
+        db.insert(b"k", val)?;
+        db.flush()?;
+        engine.create_backup(&db)?;
+        db.close()?;
+    }
+    assert_eq!(
+        std::fs::read_dir(dir.path().join("backups/sst"))?.count(),
+        2
+    );
+
+    for (id, val) in [(1, "1"), (2, "2")] {
+        engine.verify_backup(id)?;
+        let target = dir.path().join(format!("restored-{id}"));
+        engine.restore_backup(id, &target)?;
+        assert_eq!(Kepler::new(&target)?.get(b"k")?, Some(Bytes::from(val)));
+    }
+    Ok(())
+}
+
+#[test]
+fn close_flushes_imm_tables_and_rejects_later_calls() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let db = Kepler::new(dir.path())?;
+    let other = db.open_tree("other")?;
+
+    let big_val = vec![1u8; 32 * 1024 * 1024]; //ACTIVE_CAP_MAX = 32MB
+    db.insert(b"big", &big_val)?;
+    other.insert(b"k", b"v")?;
+    db.close()?;
+
+    // The frozen MemTable was written out before close returned.
+    assert!(std::fs::metadata(dir.path().join("manifest-000001.log"))?.len() > 0);
+    assert!(matches!(other.insert(b"k", b"w"), Err(Error::Closed)));
+    assert!(matches!(other.get(b"k"), Err(Error::Closed)));
+    drop(other);
+
+    let db = Kepler::new(dir.path())?;
+    assert_eq!(db.get(b"big")?, Some(Bytes::copy_from_slice(&big_val)));
+    assert_eq!(db.open_tree("other")?.get(b"k")?, Some(Bytes::from("v")));
+    Ok(())
+}
+
+#[test]
+fn flush_persists_memtable_without_the_wal() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        let other = db.open_tree("other")?;
+        db.insert(b"a", b"1")?;
+        db.remove(b"gone")?;
+        other.insert(b"b", b"2")?;
+
+        db.flush()?;
+        other.flush()?;
+        other.flush()?;
+        db.flush_wal(true)?;
+        assert_eq!(db.get(b"a")?, Some(Bytes::from("1")));
+    }
+
+    for entry in std::fs::read_dir(dir.path().join("wal"))? {
+        std::fs::remove_file(entry?.path())?;
+    }
+
+    let db = Kepler::new(dir.path())?;
+    assert_eq!(db.get(b"a")?, Some(Bytes::from("1")));
+    assert_eq!(db.open_tree("other")?.get(b"b")?, Some(Bytes::from("2")));
+    Ok(())
+}
+
+#[test]
+fn write_options_choose_wal_durability() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let crashed = tempdir()?;
+    let options = Options {
+        wal_sync_interval: Some(Duration::from_millis(5)),
+        ..Options::default()
+    };
+    let no_wal = WriteOptions {
+        disable_wal: true,
+        ..WriteOptions::default()
+    };
+    let synced = WriteOptions {
+        sync: true,
+        ..WriteOptions::default()
+    };
+
+    {
+        let db = Kepler::with_options(dir.path(), options.clone())?;
+        db.insert_opt(b"volatile", b"1", &no_wal)?;
+        db.insert_opt(b"durable", b"2", &synced)?;
+        db.insert(b"periodic", b"3")?;
+        db.remove_opt(b"durable", &no_wal)?;
+        assert_eq!(db.get(b"volatile")?, Some(Bytes::from("1")));
+        assert_eq!(db.get(b"durable")?, None);
+        thread::sleep(Duration::from_millis(20));
+
+        // What a crash right now would leave on disk.
+        copy_dir(dir.path(), crashed.path())?;
+    }
+
+    let db = Kepler::with_options(crashed.path(), options.clone())?;
+    assert_eq!(db.get(b"volatile")?, None);
+    assert_eq!(db.get(b"durable")?, Some(Bytes::from("2")));
+    assert_eq!(db.get(b"periodic")?, Some(Bytes::from("3")));
+    db.close()?;
+
+    let db = Kepler::with_options(dir.path(), options)?;
+    assert_eq!(db.get(b"volatile")?, Some(Bytes::from("1")));
+    assert_eq!(db.get(b"durable")?, None);
+    assert_eq!(db.get(b"periodic")?, Some(Bytes::from("3")));
+    db.close()
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_synced_writers_are_all_durable() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let synced = WriteOptions {
+        sync: true,
+        ..WriteOptions::default()
+    };
+
+    {
+        let db = Kepler::new(dir.path())?;
+        let handles: Vec<_> = (0..8u32)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || -> kepler::Result<()> {
+                    for i in 0..50u32 {
+                        let key = format!("{t}-{i}");
+                        if i % 5 == 0 {
+                            let mut batch = WriteBatch::new();
+                            batch.put(key.as_bytes(), b"batch");
+                            db.write_opt(batch, &synced)?;
+                        } else {
+                            db.insert_opt(key.as_bytes(), b"put", &synced)?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+    }
+
+    let db = Kepler::new(dir.path())?;
+    for t in 0..8u32 {
+        for i in 0..50u32 {
+            let expected = if i % 5 == 0 { "batch" } else { "put" };
+            let key = format!("{t}-{i}");
+            assert_eq!(db.get(key.as_bytes())?, Some(Bytes::from(expected)));
+        }
+    }
+    db.close()
+}
+
+#[test]
+fn reopen_deletes_flushed_wal_files() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    for round in 0..3u8 {
+        let db = Kepler::new(dir.path())?;
+        db.insert(&[round], b"v")?;
+        db.flush()?;
+        db.close()?;
+    }
+
+    let db = Kepler::new(dir.path())?;
+    assert_eq!(std::fs::read_dir(dir.path().join("wal"))?.count(), 1);
+    for round in 0..3u8 {
+        assert_eq!(db.get(&[round])?, Some(Bytes::from("v")));
+    }
+    db.close()
+}
+
+#[test]
+fn reopen_clears_orphan_ssts_and_reports_missing_ones() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let sst_dir = dir.path().join("sst");
+
+    {
+        let db = Kepler::new(dir.path())?;
+        db.insert(b"k", b"v")?;
+        db.flush()?;
+        db.close()?;
+    }
+
+    let live: Vec<_> = std::fs::read_dir(&sst_dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    // A table half-written by a crashed flush, never recorded in the manifest,
+    // and an older unlisted one next to an earlier orphan of the same name.
+    std::fs::write(sst_dir.join("sst-000999.log"), b"partial")?;
+    std::fs::write(sst_dir.join("sst-000000.log"), b"unlisted")?;
+    std::fs::create_dir(dir.path().join("lost"))?;
+    std::fs::write(dir.path().join("lost/sst-000000.log"), b"earlier")?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        assert_eq!(db.get(b"k")?, Some(Bytes::from("v")));
+        assert!(!sst_dir.join("sst-000999.log").exists());
+        assert!(!dir.path().join("lost/sst-000999.log").exists());
+        assert!(!sst_dir.join("sst-000000.log").exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("lost/sst-000000.log"))?,
+            b"earlier"
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("lost/sst-000000.log.1"))?,
+            b"unlisted"
+        );
+        db.close()?;
+    }
+
+    for path in live.iter() {
+        std::fs::remove_file(path)?;
+    }
+    assert!(matches!(
+        Kepler::new(dir.path()),
+        Err(Error::SstCorrupted { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn missing_current_with_ssts_refuses_to_open() -> kepler::Result<()> {
+    let dir = tempdir()?;
+
+    {
+        let db = Kepler::new(dir.path())?;
+        db.insert(b"k", b"v")?;
+        db.flush()?;
+        db.close()?;
+    }
+
+    let sst_count =
+        || -> std::io::Result<usize> { Ok(std::fs::read_dir(dir.path().join("sst"))?.count()) };
+    let before = sst_count()?;
+    std::fs::remove_file(dir.path().join("CURRENT"))?;
+
+    assert!(matches!(Kepler::new(dir.path()), Err(Error::Corrupted)));
+    assert_eq!(sst_count()?, before);
+    Ok(())
+}
+
+#[test]
+fn files_are_published_whole_and_temp_leftovers_removed() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let no_tmp = |sub: &str| -> std::io::Result<bool> {
+        Ok(std::fs::read_dir(dir.path().join(sub))?
+            .all(|e| e.is_ok_and(|e| e.path().extension().is_none_or(|ext| ext != "tmp"))))
+    };
+
+    {
+        let db = Kepler::new(dir.path())?;
+        db.insert(b"k", b"v")?;
+        db.flush()?;
+        assert!(no_tmp("")? && no_tmp("sst")? && no_tmp("wal")?);
+        db.close()?;
+    }
+
+    // Files a crash left under their temp names.
+    std::fs::write(dir.path().join("sst/sst-000999.tmp"), b"partial")?;
+    std::fs::write(dir.path().join("wal/wal-000999.tmp"), b"")?;
+    std::fs::write(dir.path().join("manifest-000999.tmp"), b"partial")?;
+
+    let db = Kepler::new(dir.path())?;
+    assert!(no_tmp("")? && no_tmp("sst")? && no_tmp("wal")?);
+    assert_eq!(db.get(b"k")?, Some(Bytes::from("v")));
+    db.close()
+}
+
+fn sst_bytes(path: &std::path::Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path.join("sst"))? {
+        total += entry?.metadata()?.len();
+    }
+    Ok(total)
+}
+
+#[test]
+fn lz_compression_shrinks_json_ssts() -> kepler::Result<()> {
+    let plain = tempdir()?;
+    let packed = tempdir()?;
+    let json = |i: u32| format!(r#"{{"id":{i},"name":"user-{i}","tags":["a","b"],"active":true}}"#);
+
+    for (dir, compression) in [(&plain, Compression::None), (&packed, Compression::Lz)] {
+        let options = Options {
+            compression,
+            ..Options::default()
+        };
+        let db = Kepler::with_options(dir.path(), options)?;
+        for i in 0..2000 {
+            db.insert(format!("user-{:05}", i).as_bytes(), json(i).as_bytes())?;
+        }
+        db.flush()?;
+        db.close()?;
+    }
+    assert!(sst_bytes(packed.path())? * 2 < sst_bytes(plain.path())?);
+
+    // Compressed blocks are read whatever codec new tables use.
+    let db = Kepler::new(packed.path())?;
+    assert_eq!(db.get(b"user-01234")?, Some(Bytes::from(json(1234))));
+    assert_eq!(db.iter()?.count(), 2000);
+    Ok(())
+}
+
+/// Stores runs of a byte as (count, byte) pairs.
+struct RunLength;
+
+impl Compressor for RunLength {
+    fn id(&self) -> u8 {
+        7
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for run in data.chunk_by(|a, b| a == b) {
+            for part in run.chunks(255) {
+                out.extend_from_slice(&[part.len() as u8, part[0]]);
+            }
+        }
+        out
+    }
+
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let pairs = data.chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return None;
+        }
+        Some(pairs.flat_map(|p| vec![p[1]; p[0] as usize]).collect())
+    }
+}
+
+#[test]
+fn custom_codec_must_stay_registered_to_open_its_ssts() -> kepler::Result<()> {
+    let dir = tempdir()?;
+    let with_codec = || Options {
+        compression: Compression::Custom(Arc::new(RunLength)),
+        ..Options::default()
+    };
+
+    {
+        let db = Kepler::with_options(dir.path(), with_codec())?;
+        db.insert(b"k", &[b'x'; 1000])?;
+        db.flush()?;
+        db.close()?;
+    }
+
+    // Opening reads the last block of every SST.
+    assert!(matches!(
+        Kepler::new(dir.path()),
+        Err(Error::UnknownCompression { id: 7 })
+    ));
+
+    let db = Kepler::with_options(dir.path(), with_codec())?;
+    assert_eq!(db.get(b"k")?, Some(Bytes::from(vec![b'x'; 1000])));
+    Ok(())
+}